@@ -0,0 +1,68 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    os::unix::net::UnixListener,
+    path::PathBuf,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use chrometrace::collector::{self, Options};
+use clap::Parser;
+
+/// Collect the events of processes tracing to a Unix socket into one trace.
+///
+/// Processes stream to the collector when `CHROMETRACER_SOCKET` names its socket.
+#[derive(Parser)]
+#[command(name = "chrometrace-collector")]
+struct Cli {
+    socket: PathBuf,
+    #[arg(short, long, default_value = "trace.json")]
+    output: PathBuf,
+    /// Exit once every process that connected has disconnected
+    #[arg(long)]
+    exit_when_idle: bool,
+}
+
+/// Set on SIGINT or SIGTERM, so the collector finishes the trace before exiting.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+fn run(cli: Cli) -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        if unsafe { libc::signal(signal, stop as *const () as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // A socket left behind by a collector that was killed.
+    if fs::metadata(&cli.socket).is_ok() {
+        fs::remove_file(&cli.socket)?;
+    }
+    let listener = UnixListener::bind(&cli.socket)?;
+    let writer = BufWriter::new(File::create(&cli.output)?);
+    let result = collector::serve(
+        listener,
+        writer,
+        Options {
+            exit_when_idle: cli.exit_when_idle,
+            stop: Some(&STOP),
+        },
+    );
+    let _ = fs::remove_file(&cli.socket);
+    result
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("chrometrace-collector: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Collects the events streamed by chrometracer processes over a Unix socket into one trace.
+//!
+//! Every connection starts with a hello anchoring its timestamps to `CLOCK_MONOTONIC`, which
+//! the collector uses to rebase them onto its own start, so all processes share one timeline.
+//! Events are written as they arrive; when a process crashes, everything it sent up to the
+//! last complete frame is kept and the other processes are unaffected.
+//!
+//! Processes in different pid namespaces may share a pid. Like `chrometrace merge`, the
+//! collector gives a process whose pid was already seen a new one, above the range of real
+//! pids so it cannot clash with a process connecting later, and names it after its own pid.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrometracer::wire::{self, Frame, Hello};
+use serde_json::{json, Value};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Return once every process that connected has disconnected.
+    pub exit_when_idle: bool,
+    /// Return once this is set, such as by a signal handler.
+    pub stop: Option<&'static AtomicBool>,
+}
+
+/// The output trace, shared by all connections.
+struct Output<W> {
+    writer: W,
+    empty: bool,
+    /// Whether the closing `]` was written; later events are dropped.
+    finished: bool,
+    /// Pids of the processes seen so far, after renumbering.
+    pids: BTreeSet<u64>,
+}
+
+impl<W: Write> Output<W> {
+    fn write(&mut self, event: &Value) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        if !self.empty {
+            self.writer.write_all(b",\n")?;
+        }
+        self.empty = false;
+        serde_json::to_writer(&mut self.writer, event)?;
+        Ok(())
+    }
+}
+
+/// Accepts connections on `listener` and writes their events to `writer` as a JSON trace.
+///
+/// Runs until accepting fails, [`Options::stop`] is set or, with [`Options::exit_when_idle`],
+/// until no process is connected any more. The closing `]` is only written then; readers of
+/// the trace accept it missing when the collector is killed instead.
+pub fn serve<W: Write + Send + 'static>(listener: UnixListener, writer: W, options: Options) -> io::Result<()> {
+    let start = Instant::now();
+    let (monotonic, realtime) = wire::clock_anchor(start);
+    let anchor = monotonic.unwrap_or_default();
+
+    let output = Arc::new(Mutex::new(Output {
+        writer,
+        empty: true,
+        finished: false,
+        pids: BTreeSet::new(),
+    }));
+    {
+        let mut output = output.lock().unwrap();
+        output.writer.write_all(b"[\n")?;
+        // The collected trace can itself be merged with others.
+        let mut args = json!({ "monotonic_ns": anchor.to_string() });
+        if let Some(realtime) = realtime {
+            args["realtime_ns"] = json!(realtime.to_string());
+        }
+        output.write(&json!({"name": "clock_anchor", "ph": "M", "ts": 0, "pid": std::process::id(), "tid": 0, "args": args}))?;
+        output.writer.flush()?;
+    }
+
+    let connected = Arc::new(AtomicUsize::new(0));
+    let mut accepted = 0usize;
+    listener.set_nonblocking(options.exit_when_idle || options.stop.is_some())?;
+    loop {
+        if options.stop.is_some_and(|stop| stop.load(Ordering::SeqCst)) {
+            break;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                accepted += 1;
+                connected.fetch_add(1, Ordering::SeqCst);
+                let (output, connected) = (output.clone(), connected.clone());
+                thread::spawn(move || {
+                    if let Err(e) = receive(stream, anchor, &output) {
+                        eprintln!("chrometrace-collector: {}", e);
+                    }
+                    connected.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if options.exit_when_idle && accepted > 0 && connected.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut output = output.lock().unwrap();
+    output.finished = true;
+    output.writer.write_all(b"\n]\n")?;
+    output.writer.flush()
+}
+
+/// Writes the events of one process, shifted by the distance of its anchor from `anchor`.
+fn receive<W: Write>(stream: UnixStream, anchor: u64, output: &Mutex<Output<W>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let Some(Frame::Hello(hello)) = wire::read_frame(&mut reader, 0)? else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "a stream must start with a hello"));
+    };
+    let Hello { pid: original, name, monotonic_ns, .. } = hello;
+    let offset = monotonic_ns.map_or(0.0, |ns| (ns as f64 - anchor as f64) / 1000.0);
+
+    let pid = {
+        let mut output = output.lock().unwrap();
+        let pid = match output.pids.contains(&original) {
+            true => (1 << 32..).find(|pid| !output.pids.contains(pid)).unwrap(),
+            false => original,
+        };
+        output.pids.insert(pid);
+
+        let name = match (pid == original, name.is_empty()) {
+            (true, _) => name,
+            (false, true) => format!("pid {}", original),
+            (false, false) => format!("{} (pid {})", name, original),
+        };
+        if !name.is_empty() {
+            output.write(&json!({"name": "process_name", "ph": "M", "ts": 0, "pid": pid, "tid": 0, "args": { "name": name }}))?;
+        }
+        pid
+    };
+
+    loop {
+        let event = match wire::read_frame(&mut reader, pid) {
+            Ok(Some(Frame::Event(event))) => event,
+            Ok(None) => return Ok(()),
+            Ok(Some(Frame::Hello(_))) => continue,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                eprintln!("chrometrace-collector: process {} disconnected in the middle of an event", original);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if event.name == "clock_anchor" {
+            continue;
+        }
+
+        let mut value = chrometracer::reader::event_to_value(&event)?;
+        if value["ph"] != "M" {
+            value["ts"] = json!(((event.ts + offset) * 1000.0).round() / 1000.0);
+        }
+        // Flow ids are only unique within a process.
+        if matches!(value["ph"].as_str(), Some("s" | "t" | "f")) {
+            value["id"] = json!(format!("{}.{}", pid, event.id));
+        }
+
+        let mut output = output.lock().unwrap();
+        output.write(&value)?;
+        if reader.buffer().is_empty() {
+            output.writer.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use chrometracer::{ChromeEvent, EventType, Trace};
+
+    use super::*;
+
+    /// A writer whose contents remain readable after the collector took it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(name: &str, ph: EventType, ts: f64) -> ChromeEvent {
+        let mut builder = ChromeEvent::builder(SystemTime::UNIX_EPOCH);
+        builder.name(name.to_string()).ph(ph).ts(ts).dur(Some(10.0)).tid(1).id("1".to_string());
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn processes() {
+        let path = std::env::temp_dir().join(format!("chrometrace-collector-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let output = Shared::default();
+        let collector = {
+            let output = output.clone();
+            thread::spawn(move || {
+                let options = Options {
+                    exit_when_idle: true,
+                    ..Default::default()
+                };
+                serve(listener, output, options)
+            })
+        };
+
+        // Later than the collector's own anchor, whenever its thread got to take it.
+        let anchor = wire::clock_anchor(Instant::now()).0.unwrap() + 1_000_000_000;
+        let mut first = UnixStream::connect(&path).unwrap();
+        let mut second = UnixStream::connect(&path).unwrap();
+        for (stream, pid, shift) in [(&mut first, 10, 0), (&mut second, 20, 5_000_000)] {
+            let hello = Hello {
+                pid,
+                name: format!("worker-{}", pid),
+                monotonic_ns: Some(anchor + shift),
+                realtime_ns: None,
+            };
+            wire::write_hello(stream, &hello).unwrap();
+            wire::write_event(stream, &event("work", EventType::Complete, 100.0)).unwrap();
+            wire::write_event(stream, &event("wake", EventType::FlowStart, 100.0)).unwrap();
+        }
+        // The second process crashes in the middle of an event.
+        second.write_all(&[100, 0, 0, 0, 2]).unwrap();
+        drop((first, second));
+
+        collector.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let trace = Trace::read(output.0.lock().unwrap().as_slice()).unwrap();
+        let work: Vec<_> = trace.spans_named("work").collect();
+        assert_eq!(work.len(), 2);
+        let (first, second) = match work[0].pid {
+            10 => (work[0], work[1]),
+            _ => (work[1], work[0]),
+        };
+        assert_eq!(second.from - first.from, Duration::from_millis(5));
+        assert_eq!(trace.flows.len(), 2);
+    }
+
+    #[test]
+    fn stopped() {
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        let path = std::env::temp_dir().join(format!("chrometrace-collector-stop-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let output = Shared::default();
+        let collector = {
+            let output = output.clone();
+            thread::spawn(move || {
+                let options = Options {
+                    stop: Some(&STOP),
+                    ..Default::default()
+                };
+                serve(listener, output, options)
+            })
+        };
+
+        // Two processes with the same pid, from different pid namespaces, still connected.
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            let hello = Hello {
+                pid: 1,
+                name: "worker".to_string(),
+                monotonic_ns: None,
+                realtime_ns: None,
+            };
+            wire::write_hello(&mut stream, &hello).unwrap();
+            wire::write_event(&mut stream, &event("work", EventType::Complete, 100.0)).unwrap();
+            streams.push(stream);
+        }
+        while String::from_utf8_lossy(&output.0.lock().unwrap()).matches(r#""name":"work""#).count() < 2 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        STOP.store(true, Ordering::SeqCst);
+        collector.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let output = output.0.lock().unwrap();
+        assert!(output.ends_with(b"]\n"));
+        let trace = Trace::read(output.as_slice()).unwrap();
+        let pids: BTreeSet<_> = trace.spans_named("work").map(|s| s.pid).collect();
+        assert_eq!(pids.len(), 2);
+        assert!(String::from_utf8_lossy(&output).contains(r#""name":"worker (pid 1)""#));
+    }
+}
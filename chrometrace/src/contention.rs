@@ -0,0 +1,155 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrometracer::Trace;
+use serde::Serialize;
+
+use crate::{micros, stats::percentile, Format};
+
+/// How much one lock, identified by its label, was waited for and held.
+#[derive(Clone, Debug, Serialize)]
+pub struct Contention {
+    pub label: String,
+    pub acquisitions: usize,
+    /// Acquisitions that had to wait.
+    pub contended: usize,
+    pub total_wait_us: f64,
+    pub p95_wait_us: f64,
+    pub max_wait_us: f64,
+    pub total_held_us: f64,
+    pub max_held_us: f64,
+}
+
+/// Summarizes the `lock.wait` and `lock.held` spans recorded by `chrometracer::sync`, most
+/// waited-for locks first.
+pub fn compute(trace: &Trace) -> Vec<Contention> {
+    #[derive(Default)]
+    struct Lock {
+        waits: Vec<Duration>,
+        held: Vec<Duration>,
+    }
+
+    let mut locks: BTreeMap<&str, Lock> = BTreeMap::new();
+    for span in &trace.spans {
+        match span.cat.as_str() {
+            "lock.wait" => locks.entry(&span.name).or_default().waits.push(span.duration()),
+            "lock.held" => locks.entry(&span.name).or_default().held.push(span.duration()),
+            _ => {}
+        }
+    }
+
+    let mut contention: Vec<Contention> = locks
+        .into_iter()
+        .map(|(label, mut lock)| {
+            lock.waits.sort();
+            Contention {
+                label: label.to_string(),
+                acquisitions: lock.held.len(),
+                contended: lock.waits.len(),
+                total_wait_us: micros(lock.waits.iter().sum()),
+                p95_wait_us: micros(percentile(&lock.waits, 95.0)),
+                max_wait_us: micros(lock.waits.last().copied().unwrap_or_default()),
+                total_held_us: micros(lock.held.iter().sum()),
+                max_held_us: micros(lock.held.iter().max().copied().unwrap_or_default()),
+            }
+        })
+        .collect();
+
+    contention.sort_by(|a, b| {
+        b.total_wait_us
+            .total_cmp(&a.total_wait_us)
+            .then_with(|| a.label.cmp(&b.label))
+    });
+    contention
+}
+
+pub fn write<W: Write>(writer: &mut W, contention: &[Contention], format: Format) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *writer, contention)?;
+            writeln!(writer)
+        }
+        Format::Csv => {
+            writeln!(writer, "label,acquisitions,contended,total_wait_us,p95_wait_us,max_wait_us,total_held_us,max_held_us")?;
+            for c in contention {
+                writeln!(
+                    writer,
+                    "\"{}\",{},{},{:.3},{:.3},{:.3},{:.3},{:.3}",
+                    c.label.replace('"', "\"\""),
+                    c.acquisitions,
+                    c.contended,
+                    c.total_wait_us,
+                    c.p95_wait_us,
+                    c.max_wait_us,
+                    c.total_held_us,
+                    c.max_held_us
+                )?;
+            }
+            Ok(())
+        }
+        Format::Table => {
+            let width = contention.iter().map(|c| c.label.len()).max().unwrap_or(0).max(5);
+            writeln!(
+                writer,
+                "{:<width$} {:>8} {:>9} {:>13} {:>13} {:>13} {:>13} {:>13}",
+                "label",
+                "acquired",
+                "contended",
+                "wait(us)",
+                "p95 wait(us)",
+                "max wait(us)",
+                "held(us)",
+                "max held(us)",
+                width = width
+            )?;
+            for c in contention {
+                writeln!(
+                    writer,
+                    "{:<width$} {:>8} {:>9} {:>13.3} {:>13.3} {:>13.3} {:>13.3} {:>13.3}",
+                    c.label,
+                    c.acquisitions,
+                    c.contended,
+                    c.total_wait_us,
+                    c.p95_wait_us,
+                    c.max_wait_us,
+                    c.total_held_us,
+                    c.max_held_us,
+                    width = width
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks() {
+        let trace = Trace::read(
+            r#"[
+            {"name":"state","cat":"lock.held","ph":"X","ts":0,"dur":50,"pid":1,"tid":1},
+            {"name":"state","cat":"lock.wait","ph":"X","ts":10,"dur":40,"pid":1,"tid":2},
+            {"name":"state","cat":"lock.held","ph":"X","ts":50,"dur":5,"pid":1,"tid":2},
+            {"name":"config","cat":"lock.held","ph":"X","ts":0,"dur":1,"pid":1,"tid":3},
+            {"name":"state","ph":"X","ts":0,"dur":100,"pid":1,"tid":3}
+        ]"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let contention = compute(&trace);
+        assert_eq!(contention.len(), 2);
+
+        let state = &contention[0];
+        assert_eq!(state.label, "state");
+        assert_eq!((state.acquisitions, state.contended), (2, 1));
+        assert_eq!((state.total_wait_us, state.total_held_us, state.max_held_us), (40.0, 55.0, 50.0));
+        assert_eq!(contention[1].contended, 0);
+    }
+}
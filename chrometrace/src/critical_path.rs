@@ -0,0 +1,309 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrometracer::Trace;
+use serde_json::{json, Value};
+
+use crate::micros;
+
+/// Part of a span's own time that lies on the critical path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub span: usize,
+    pub from: Duration,
+    pub to: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Contribution {
+    pub name: String,
+    pub pid: u64,
+    pub tid: u64,
+    pub time: Duration,
+    pub segments: usize,
+}
+
+/// A flow arriving at a span, coming from another span.
+struct Incoming {
+    at: Duration,
+    from_span: usize,
+    from_ts: Duration,
+}
+
+struct Walker<'a> {
+    trace: &'a Trace,
+    children: Vec<Vec<usize>>,
+    incoming: Vec<Vec<Incoming>>,
+    start: Duration,
+    budget: usize,
+    segments: Vec<Segment>,
+}
+
+impl Walker<'_> {
+    fn push(&mut self, span: usize, from: Duration, to: Duration) {
+        let from = from.max(self.start);
+        if from < to {
+            self.segments.push(Segment { span, from, to });
+        }
+    }
+
+    /// Walks backwards from `cursor` in `root`, always following whatever finished last: a
+    /// nested span, or a flow arriving from another span. After following a flow, the walk
+    /// goes on from the start of the span it arrived from into that span's parents.
+    ///
+    /// Nested spans are kept on an explicit stack rather than the call stack, so deep nesting
+    /// and long chains of flows cannot overflow it.
+    fn walk(&mut self, root: usize, cursor: Duration) {
+        let spans = &self.trace.spans;
+        // The spans being walked, innermost last, with the cursor in each.
+        let mut stack = vec![(root, cursor)];
+        // Whether the outermost span on the stack continues into its parent once reached.
+        let mut up = false;
+
+        while let Some(&(span, cursor)) = stack.last() {
+            if cursor <= self.start || self.budget == 0 {
+                return;
+            }
+            self.budget -= 1;
+
+            let child = self.children[span]
+                .iter()
+                .copied()
+                .filter(|&c| spans[c].to <= cursor && spans[c].from < cursor)
+                .max_by_key(|&c| spans[c].to);
+            let flow = self.incoming[span]
+                .iter()
+                .filter(|f| f.at <= cursor && f.from_span != span)
+                .max_by_key(|f| f.at);
+
+            let child = child.filter(|&c| flow.is_none_or(|f| spans[c].to >= f.at));
+            match (child, flow) {
+                (Some(c), _) => {
+                    self.push(span, spans[c].to, cursor);
+                    stack.push((c, spans[c].to));
+                }
+                (_, Some(flow)) => {
+                    let (at, from_span, from_ts) = (flow.at, flow.from_span, flow.from_ts);
+                    self.push(span, at, cursor);
+                    stack.clear();
+                    stack.push((from_span, from_ts.min(at)));
+                    up = true;
+                }
+                (None, None) => {
+                    self.push(span, spans[span].from, cursor);
+                    stack.pop();
+                    match stack.last_mut() {
+                        Some((_, cursor)) => *cursor = spans[span].from,
+                        None if up => {
+                            if let Some(parent) = spans[span].parent {
+                                stack.push((parent, spans[span].from));
+                            }
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The longest top-level span, or the first span called `name`.
+pub fn find_root(trace: &Trace, name: Option<&str>) -> Option<usize> {
+    match name {
+        Some(name) => trace.spans.iter().position(|s| s.name == name),
+        None => (0..trace.spans.len())
+            .filter(|&i| trace.spans[i].parent.is_none() && !trace.spans[i].is_async)
+            .max_by_key(|&i| trace.spans[i].duration()),
+    }
+}
+
+/// Computes the chain of work, across threads, that determined when `root` finished.
+///
+/// Starting at the end of `root`, the path steps back to whichever nested span finished last
+/// or, through a flow, to the span on another thread that caused the current one to continue.
+pub fn critical_path(trace: &Trace, root: usize) -> Vec<Segment> {
+    let mut children = vec![Vec::new(); trace.spans.len()];
+    for (index, span) in trace.spans.iter().enumerate() {
+        if let Some(parent) = span.parent {
+            children[parent].push(index);
+        }
+    }
+
+    let mut incoming: Vec<Vec<Incoming>> = (0..trace.spans.len()).map(|_| Vec::new()).collect();
+    for flow in &trace.flows {
+        for pair in flow.points.windows(2) {
+            if let (Some(from_span), Some(to_span)) = (pair[0].span, pair[1].span) {
+                incoming[to_span].push(Incoming {
+                    at: pair[1].ts,
+                    from_span,
+                    from_ts: pair[0].ts,
+                });
+            }
+        }
+    }
+
+    let mut walker = Walker {
+        trace,
+        children,
+        incoming,
+        start: trace.spans[root].from,
+        budget: 4 * (trace.spans.len() + trace.flows.len()) + 16,
+        segments: Vec::new(),
+    };
+    walker.walk(root, trace.spans[root].to);
+
+    let mut segments = walker.segments;
+    segments.reverse();
+    segments
+}
+
+/// Time on the critical path per span name and thread, largest first.
+pub fn summarize(trace: &Trace, segments: &[Segment]) -> Vec<Contribution> {
+    let mut contributions: BTreeMap<(&str, u64, u64), Contribution> = BTreeMap::new();
+    for segment in segments {
+        let span = &trace.spans[segment.span];
+        let contribution = contributions
+            .entry((&span.name, span.pid, span.tid))
+            .or_insert_with(|| Contribution {
+                name: span.name.clone(),
+                pid: span.pid,
+                tid: span.tid,
+                time: Duration::ZERO,
+                segments: 0,
+            });
+        contribution.time += segment.to - segment.from;
+        contribution.segments += 1;
+    }
+
+    let mut contributions: Vec<Contribution> = contributions.into_values().collect();
+    contributions.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)));
+    contributions
+}
+
+pub fn write_summary<W: Write>(writer: &mut W, trace: &Trace, root: usize, contributions: &[Contribution]) -> io::Result<()> {
+    let root = &trace.spans[root];
+    let total: Duration = contributions.iter().map(|c| c.time).sum();
+    writeln!(
+        writer,
+        "critical path of \"{}\" ({:.3} us), {:.3} us attributed",
+        root.name,
+        micros(root.duration()),
+        micros(total)
+    )?;
+
+    let width = contributions.iter().map(|c| c.name.len()).max().unwrap_or(0).max(4);
+    writeln!(writer, "{:<width$} {:>8} {:>8} {:>14} {:>7}", "span", "tid", "segments", "time(us)", "share", width = width)?;
+    for c in contributions {
+        writeln!(
+            writer,
+            "{:<width$} {:>8} {:>8} {:>14.3} {:>6.1}%",
+            c.name,
+            c.tid,
+            c.segments,
+            micros(c.time),
+            micros(c.time) * 100.0 / micros(root.duration()).max(f64::MIN_POSITIVE),
+            width = width
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `events` followed by one highlighted slice per segment, nested in the span it covers.
+pub fn annotate<W: Write>(writer: &mut W, events: &[Value], trace: &Trace, segments: &[Segment]) -> io::Result<()> {
+    writer.write_all(b"[\n")?;
+    let overlay = segments.iter().map(|segment| {
+        let span = &trace.spans[segment.span];
+        json!({
+            "name": "critical path",
+            "cat": "critical_path",
+            "ph": "X",
+            "ts": micros(segment.from),
+            "dur": micros(segment.to - segment.from),
+            "pid": span.pid,
+            "tid": span.tid,
+            "cname": "terrible",
+            "args": { "span": span.name },
+        })
+    });
+
+    for (i, event) in events.iter().cloned().chain(overlay).enumerate() {
+        if i > 0 {
+            writer.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut *writer, &event)?;
+    }
+    writer.write_all(b"\n]\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = r#"[
+        {"name":"main","ph":"X","ts":0,"dur":100,"pid":1,"tid":1},
+        {"name":"prepare","ph":"X","ts":0,"dur":10,"pid":1,"tid":1},
+        {"name":"wait","ph":"X","ts":10,"dur":80,"pid":1,"tid":1},
+        {"name":"produce","ph":"X","ts":5,"dur":70,"pid":1,"tid":2},
+        {"name":"item","cat":"q","id":1,"ph":"s","ts":74,"pid":1,"tid":2},
+        {"name":"item","cat":"q","id":1,"ph":"f","ts":85,"pid":1,"tid":1},
+        {"name":"busy","ph":"X","ts":20,"dur":30,"pid":1,"tid":2}
+    ]"#;
+
+    #[test]
+    fn across_threads() {
+        let trace = Trace::read(TRACE.as_bytes()).unwrap();
+        let root = find_root(&trace, None).unwrap();
+        assert_eq!(trace.spans[root].name, "main");
+
+        let segments = critical_path(&trace, root);
+        let names: Vec<&str> = segments.iter().map(|s| trace.spans[s.span].name.as_str()).collect();
+        assert_eq!(names, ["produce", "busy", "produce", "wait", "main"]);
+        assert_eq!(segments[0].from, Duration::from_micros(5));
+        assert_eq!(segments.last().unwrap().to, Duration::from_micros(100));
+
+        let contributions = summarize(&trace, &segments);
+        assert_eq!(contributions[0].name, "produce");
+        assert_eq!(contributions[0].time, Duration::from_micros(39));
+        assert_eq!(contributions[0].tid, 2);
+    }
+
+    #[test]
+    fn long_chain() {
+        // Work handed back and forth between two threads many times.
+        let hops = 50_000;
+        let mut events = Vec::new();
+        for i in 0..hops {
+            let (ts, tid) = (i * 10, 1 + i % 2);
+            events.push(format!(r#"{{"name":"step","ph":"X","ts":{},"dur":9,"pid":1,"tid":{}}}"#, ts, tid));
+            if i + 1 < hops {
+                let flow = format!(r#""name":"next","cat":"c","id":{},"pid":1"#, i);
+                events.push(format!(r#"{{{},"ph":"s","ts":{},"tid":{}}}"#, flow, ts + 8, tid));
+                events.push(format!(r#"{{{},"ph":"f","bp":"e","ts":{},"tid":{}}}"#, flow, ts + 10, 2 - i % 2));
+            }
+        }
+        // Waited for on a third thread.
+        let end = hops * 10;
+        events.push(format!(r#"{{"name":"main","ph":"X","ts":0,"dur":{},"pid":1,"tid":3}}"#, end + 10));
+        events.push(format!(r#"{{"name":"done","cat":"c","id":"done","ph":"s","ts":{},"pid":1,"tid":{}}}"#, end - 2, 2 - hops % 2));
+        events.push(format!(r#"{{"name":"done","cat":"c","id":"done","ph":"f","bp":"e","ts":{},"pid":1,"tid":3}}"#, end + 5));
+        let trace = Trace::read(format!("[{}]", events.join(",")).as_bytes()).unwrap();
+
+        let root = find_root(&trace, Some("main")).unwrap();
+        let segments = critical_path(&trace, root);
+        assert_eq!(segments.iter().filter(|s| trace.spans[s.span].name == "step").count(), hops as usize);
+    }
+
+    #[test]
+    fn annotated() {
+        let trace = Trace::read(TRACE.as_bytes()).unwrap();
+        let segments = critical_path(&trace, 0);
+
+        let mut output = Vec::new();
+        annotate(&mut output, &[json!({"name":"main","ph":"X","ts":0,"dur":100,"pid":1,"tid":1})], &trace, &segments).unwrap();
+        let annotated = Trace::read(output.as_slice()).unwrap();
+        assert_eq!(annotated.spans_named("critical path").count(), segments.len());
+    }
+}
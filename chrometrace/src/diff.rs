@@ -0,0 +1,382 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+};
+
+use chrometracer::Trace;
+use serde::Serialize;
+
+use crate::{fold, micros, Format};
+
+/// How spans of the two traces are matched up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Key {
+    Name,
+    Stack,
+}
+
+/// Limits that make a comparison fail.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Thresholds {
+    /// Largest allowed increase of the mean duration, in percent. Only significant increases
+    /// count, except for spans too rare to test, with fewer than two samples on a side, whose
+    /// raw increase counts.
+    pub max_regression: Option<f64>,
+    /// Largest allowed change of the call count, in percent.
+    pub max_count_change: Option<f64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Delta {
+    pub key: String,
+    pub before_count: usize,
+    pub after_count: usize,
+    pub before_mean_us: f64,
+    pub after_mean_us: f64,
+    pub before_total_us: f64,
+    pub after_total_us: f64,
+    /// Change of the mean duration in percent, if the span ran in both traces.
+    pub mean_change: Option<f64>,
+    /// Welch's t statistic of the durations, if both sides have at least two samples.
+    pub t: Option<f64>,
+    /// Whether the change of the mean is significant at the 95% level, against the t
+    /// distribution with the Welch–Satterthwaite degrees of freedom.
+    pub significant: bool,
+    pub exceeded: bool,
+}
+
+fn mean_variance(samples: &[f64]) -> (f64, f64) {
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    let variance = if samples.len() > 1 {
+        samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1.0)
+    } else {
+        0.0
+    };
+    (mean, variance)
+}
+
+/// Two-sided 95% critical values of the t distribution, by degrees of freedom.
+const T_CRITICAL: [(f64, f64); 34] = [
+    (1.0, 12.706),
+    (2.0, 4.303),
+    (3.0, 3.182),
+    (4.0, 2.776),
+    (5.0, 2.571),
+    (6.0, 2.447),
+    (7.0, 2.365),
+    (8.0, 2.306),
+    (9.0, 2.262),
+    (10.0, 2.228),
+    (11.0, 2.201),
+    (12.0, 2.179),
+    (13.0, 2.160),
+    (14.0, 2.145),
+    (15.0, 2.131),
+    (16.0, 2.120),
+    (17.0, 2.110),
+    (18.0, 2.101),
+    (19.0, 2.093),
+    (20.0, 2.086),
+    (21.0, 2.080),
+    (22.0, 2.074),
+    (23.0, 2.069),
+    (24.0, 2.064),
+    (25.0, 2.060),
+    (26.0, 2.056),
+    (27.0, 2.052),
+    (28.0, 2.048),
+    (29.0, 2.045),
+    (30.0, 2.042),
+    (40.0, 2.021),
+    (60.0, 2.000),
+    (120.0, 1.980),
+    (f64::INFINITY, 1.960),
+];
+
+/// The critical value for the largest tabulated degrees of freedom not above `df`, which errs
+/// on the side of calling a change insignificant.
+fn t_critical(df: f64) -> f64 {
+    T_CRITICAL.iter().rev().find(|&&(at, _)| at <= df).map_or(T_CRITICAL[0].1, |&(_, t)| t)
+}
+
+/// Welch's t statistic of the change from `before` to `after`, and its degrees of freedom by
+/// the Welch–Satterthwaite equation.
+fn welch(before: &[f64], after: &[f64]) -> Option<(f64, f64)> {
+    if before.len() < 2 || after.len() < 2 {
+        return None;
+    }
+
+    let (n1, n2) = (before.len() as f64, after.len() as f64);
+    let (m1, v1) = mean_variance(before);
+    let (m2, v2) = mean_variance(after);
+    let (e1, e2) = (v1 / n1, v2 / n2);
+    let error = (e1 + e2).sqrt();
+    if error == 0.0 {
+        let t = if m1 == m2 { 0.0 } else { (m2 - m1).signum() * f64::INFINITY };
+        Some((t, n1 + n2 - 2.0))
+    } else {
+        let df = (e1 + e2).powi(2) / (e1.powi(2) / (n1 - 1.0) + e2.powi(2) / (n2 - 1.0));
+        Some(((m2 - m1) / error, df))
+    }
+}
+
+fn durations(trace: &Trace, key: Key) -> BTreeMap<String, Vec<f64>> {
+    let mut durations: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for span in &trace.spans {
+        let key = match key {
+            Key::Name => span.name.clone(),
+            Key::Stack => fold::path(trace, span),
+        };
+        durations.entry(key).or_default().push(micros(span.duration()));
+    }
+    durations
+}
+
+fn percent(before: f64, after: f64) -> Option<f64> {
+    (before > 0.0).then(|| (after - before) * 100.0 / before)
+}
+
+pub fn compare(before: &Trace, after: &Trace, key: Key, thresholds: &Thresholds) -> Vec<Delta> {
+    let before = durations(before, key);
+    let after = durations(after, key);
+    let empty = Vec::new();
+
+    let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    let mut deltas: Vec<Delta> = keys
+        .into_iter()
+        .map(|key| {
+            let b = before.get(key).unwrap_or(&empty);
+            let a = after.get(key).unwrap_or(&empty);
+            let (before_mean, after_mean) = (
+                if b.is_empty() { 0.0 } else { mean_variance(b).0 },
+                if a.is_empty() { 0.0 } else { mean_variance(a).0 },
+            );
+
+            let mean_change = if b.is_empty() || a.is_empty() {
+                None
+            } else {
+                percent(before_mean, after_mean)
+            };
+            let welch = welch(b, a);
+            let t = welch.map(|(t, _)| t);
+            let significant = welch.is_some_and(|(t, df)| t.abs() > t_critical(df));
+
+            let regressed = matches!(
+                (thresholds.max_regression, mean_change),
+                (Some(max), Some(change)) if (significant || t.is_none()) && change > max
+            );
+            let count_changed = match (thresholds.max_count_change, percent(b.len() as f64, a.len() as f64)) {
+                (Some(max), Some(change)) => change.abs() > max,
+                (Some(_), None) => !a.is_empty(),
+                _ => false,
+            };
+
+            Delta {
+                key: key.clone(),
+                before_count: b.len(),
+                after_count: a.len(),
+                before_mean_us: before_mean,
+                after_mean_us: after_mean,
+                before_total_us: b.iter().sum(),
+                after_total_us: a.iter().sum(),
+                mean_change,
+                t,
+                significant,
+                exceeded: regressed || count_changed,
+            }
+        })
+        .collect();
+
+    deltas.sort_by(|x, y| {
+        let change = |d: &Delta| (d.after_total_us - d.before_total_us).abs();
+        change(y).total_cmp(&change(x)).then_with(|| x.key.cmp(&y.key))
+    });
+    deltas
+}
+
+fn signed(value: Option<f64>, suffix: &str) -> String {
+    match value {
+        Some(value) if value.is_finite() => format!("{:+.1}{}", value, suffix),
+        Some(value) => format!("{}inf", if value > 0.0 { "+" } else { "-" }),
+        None => "-".to_string(),
+    }
+}
+
+pub fn write<W: Write>(writer: &mut W, deltas: &[Delta], format: Format) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *writer, deltas)?;
+            writeln!(writer)
+        }
+        Format::Csv => {
+            writeln!(writer, "key,before_count,after_count,before_mean_us,after_mean_us,before_total_us,after_total_us,mean_change_percent,t,significant,exceeded")?;
+            for d in deltas {
+                let optional = |v: Option<f64>| v.map(|v| format!("{:.3}", v)).unwrap_or_default();
+                writeln!(
+                    writer,
+                    "\"{}\",{},{},{:.3},{:.3},{:.3},{:.3},{},{},{},{}",
+                    d.key.replace('"', "\"\""),
+                    d.before_count,
+                    d.after_count,
+                    d.before_mean_us,
+                    d.after_mean_us,
+                    d.before_total_us,
+                    d.after_total_us,
+                    optional(d.mean_change),
+                    optional(d.t),
+                    d.significant,
+                    d.exceeded
+                )?;
+            }
+            Ok(())
+        }
+        Format::Table => {
+            let width = deltas.iter().map(|d| d.key.len()).max().unwrap_or(0).max(4);
+            writeln!(
+                writer,
+                "{:<width$} {:>16} {:>28} {:>9} {:>8}",
+                "span",
+                "count",
+                "mean(us)",
+                "change",
+                "t",
+                width = width
+            )?;
+            for d in deltas {
+                let mark = match (d.exceeded, d.significant, d.t.is_none() && d.mean_change.is_some()) {
+                    (true, _, _) => " !",
+                    (false, true, _) => " *",
+                    (false, false, true) => " ?",
+                    _ => "",
+                };
+                writeln!(
+                    writer,
+                    "{:<width$} {:>6} -> {:<6} {:>12.3} -> {:<12.3} {:>9} {:>8}{}",
+                    d.key,
+                    d.before_count,
+                    d.after_count,
+                    d.before_mean_us,
+                    d.after_mean_us,
+                    signed(d.mean_change, "%"),
+                    signed(d.t, ""),
+                    mark,
+                    width = width
+                )?;
+            }
+            writeln!(writer, "\n* significant change, ? too few samples to test, ! threshold exceeded")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(durations: &[(&str, u64)]) -> Trace {
+        let events: Vec<String> = durations
+            .iter()
+            .enumerate()
+            .map(|(i, (name, dur))| {
+                format!(
+                    r#"{{"name":"{}","ph":"X","ts":{},"dur":{},"pid":1,"tid":1}}"#,
+                    name,
+                    i * 1000,
+                    dur
+                )
+            })
+            .collect();
+        Trace::read(format!("[{}]", events.join(",")).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn regression() {
+        let before = trace(&[("a", 10), ("a", 11), ("a", 9), ("b", 5), ("b", 5)]);
+        let after = trace(&[("a", 20), ("a", 21), ("a", 19), ("b", 5), ("b", 6), ("c", 1)]);
+        let thresholds = Thresholds {
+            max_regression: Some(50.0),
+            ..Default::default()
+        };
+
+        let deltas = compare(&before, &after, Key::Name, &thresholds);
+        let a = deltas.iter().find(|d| d.key == "a").unwrap();
+        assert!(a.significant && a.exceeded);
+        assert_eq!(a.mean_change, Some(100.0));
+
+        let b = deltas.iter().find(|d| d.key == "b").unwrap();
+        assert!(!b.exceeded);
+
+        let c = deltas.iter().find(|d| d.key == "c").unwrap();
+        assert_eq!((c.before_count, c.after_count, c.mean_change), (0, 1, None));
+    }
+
+    #[test]
+    fn small_samples() {
+        // t is about 2.45 with 4 degrees of freedom, below the critical value of 2.776.
+        let before = trace(&[("a", 10), ("a", 12), ("a", 14)]);
+        let after = trace(&[("a", 14), ("a", 16), ("a", 18)]);
+        let thresholds = Thresholds {
+            max_regression: Some(10.0),
+            ..Default::default()
+        };
+
+        let a = &compare(&before, &after, Key::Name, &thresholds)[0];
+        assert!(a.t.unwrap() > 1.96);
+        assert!(!a.significant && !a.exceeded);
+
+        assert_eq!(t_critical(4.0), 2.776);
+        assert_eq!(t_critical(2.9), 4.303);
+        assert_eq!(t_critical(0.5), 12.706);
+        assert_eq!(t_critical(1e6), 1.980);
+    }
+
+    #[test]
+    fn untested() {
+        // A single sample on one side cannot be tested, so its raw change counts.
+        let before = trace(&[("a", 10), ("b", 10), ("b", 10)]);
+        let after = trace(&[("a", 30), ("b", 12)]);
+        let thresholds = Thresholds {
+            max_regression: Some(50.0),
+            ..Default::default()
+        };
+
+        let deltas = compare(&before, &after, Key::Name, &thresholds);
+        let a = deltas.iter().find(|d| d.key == "a").unwrap();
+        assert!(a.t.is_none() && a.exceeded);
+        let b = deltas.iter().find(|d| d.key == "b").unwrap();
+        assert!(b.t.is_none() && !b.exceeded);
+
+        let mut table = Vec::new();
+        write(&mut table, &deltas, Format::Table).unwrap();
+        assert!(String::from_utf8(table).unwrap().lines().any(|l| l.starts_with('b') && l.ends_with(" ?")));
+    }
+
+    #[test]
+    fn count_change() {
+        let before = trace(&[("a", 10)]);
+        let after = trace(&[("a", 10), ("a", 10)]);
+        let thresholds = Thresholds {
+            max_count_change: Some(10.0),
+            ..Default::default()
+        };
+
+        let deltas = compare(&before, &after, Key::Name, &thresholds);
+        assert!(deltas[0].exceeded);
+        assert!(!deltas[0].significant);
+    }
+
+    #[test]
+    fn stacks() {
+        let before = trace(&[("a", 10)]);
+        let after = Trace::read(
+            r#"[{"name":"b","ph":"X","ts":0,"dur":20,"pid":1,"tid":1},{"name":"a","ph":"X","ts":1,"dur":10,"pid":1,"tid":1}]"#.as_bytes(),
+        )
+        .unwrap();
+
+        let keys: Vec<String> = compare(&before, &after, Key::Stack, &Thresholds::default())
+            .into_iter()
+            .map(|d| d.key)
+            .collect();
+        assert!(keys.contains(&"b;a".to_string()) && keys.contains(&"a".to_string()));
+    }
+}
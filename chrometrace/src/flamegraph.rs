@@ -0,0 +1,198 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub title: String,
+    pub width: f64,
+    pub frame_height: f64,
+    /// Draw the root at the top instead of the bottom.
+    pub icicle: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            title: "Flame Graph".to_string(),
+            width: 1200.0,
+            frame_height: 16.0,
+            icicle: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    value: u64,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn depth(&self) -> usize {
+        self.children.values().map(|c| c.depth() + 1).max().unwrap_or(0)
+    }
+}
+
+const FONT_SIZE: f64 = 12.0;
+const CHAR_WIDTH: f64 = FONT_SIZE * 0.59;
+const MARGIN: f64 = 10.0;
+const HEADER: f64 = FONT_SIZE * 3.0;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Warm colour derived from the frame name, so the same function keeps its colour across graphs.
+fn color(name: &str) -> String {
+    let hash = name
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    let r = 205 + (hash % 50);
+    let g = (hash >> 8) % 230;
+    let b = (hash >> 16) % 55;
+    format!("rgb({},{},{})", r, g, b)
+}
+
+struct Renderer<'a, W> {
+    writer: &'a mut W,
+    options: &'a Options,
+    scale: f64,
+    total: u64,
+    height: f64,
+}
+
+impl<W: Write> Renderer<'_, W> {
+    fn frame(&mut self, name: &str, node: &Node, x: f64, depth: usize) -> io::Result<()> {
+        let width = node.value as f64 * self.scale;
+        if width < 0.1 {
+            return Ok(());
+        }
+
+        let y = if self.options.icicle {
+            HEADER + depth as f64 * self.options.frame_height
+        } else {
+            self.height - MARGIN - (depth + 1) as f64 * self.options.frame_height
+        };
+
+        let percent = node.value as f64 * 100.0 / self.total as f64;
+        writeln!(
+            self.writer,
+            "<g><title>{} ({} us, {:.2}%)</title><rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\" rx=\"2\" ry=\"2\"/>",
+            escape(name),
+            node.value,
+            percent,
+            x,
+            y,
+            width,
+            self.options.frame_height - 1.0,
+            color(name)
+        )?;
+
+        let chars = ((width - 6.0) / CHAR_WIDTH) as usize;
+        if chars >= 3 {
+            let label = if name.chars().count() > chars {
+                format!("{}..", name.chars().take(chars - 2).collect::<String>())
+            } else {
+                name.to_string()
+            };
+            writeln!(
+                self.writer,
+                "<text x=\"{:.1}\" y=\"{:.1}\">{}</text>",
+                x + 3.0,
+                y + self.options.frame_height - 4.5,
+                escape(&label)
+            )?;
+        }
+        writeln!(self.writer, "</g>")?;
+
+        let mut child_x = x;
+        for (child_name, child) in &node.children {
+            self.frame(child_name, child, child_x, depth + 1)?;
+            child_x += child.value as f64 * self.scale;
+        }
+        Ok(())
+    }
+}
+
+/// Renders folded stacks as a self-contained SVG flame graph.
+pub fn render<W: Write>(writer: &mut W, folded: &BTreeMap<String, u64>, options: &Options) -> io::Result<()> {
+    let mut root = Node::default();
+    for (stack, value) in folded {
+        root.value += value;
+        let mut node = &mut root;
+        for frame in stack.split(';') {
+            node = node.children.entry(frame.to_string()).or_default();
+            node.value += value;
+        }
+    }
+
+    let depth = root.depth().max(1);
+    let height = HEADER + depth as f64 * options.frame_height + MARGIN * 2.0;
+
+    writeln!(writer, "<?xml version=\"1.0\" standalone=\"no\"?>")?;
+    writeln!(
+        writer,
+        "<svg version=\"1.1\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" xmlns=\"http://www.w3.org/2000/svg\">",
+        w = options.width,
+        h = height
+    )?;
+    writeln!(
+        writer,
+        "<style>text {{ font-family: Verdana, sans-serif; font-size: {}px; fill: #000; }} rect:hover {{ stroke: #000; }}</style>",
+        FONT_SIZE
+    )?;
+    writeln!(writer, "<rect width=\"100%\" height=\"100%\" fill=\"#f8f8f8\"/>")?;
+    writeln!(
+        writer,
+        "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\" style=\"font-size: {}px\">{}</text>",
+        options.width / 2.0,
+        FONT_SIZE * 2.0,
+        FONT_SIZE + 5.0,
+        escape(&options.title)
+    )?;
+
+    if root.value > 0 {
+        let mut renderer = Renderer {
+            writer: &mut *writer,
+            options,
+            scale: (options.width - MARGIN * 2.0) / root.value as f64,
+            total: root.value,
+            height,
+        };
+        let mut x = MARGIN;
+        for (name, node) in &root.children {
+            renderer.frame(name, node, x, 0)?;
+            x += node.value as f64 * renderer.scale;
+        }
+    }
+
+    writeln!(writer, "</svg>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg() {
+        let folded: BTreeMap<String, u64> = [("main", 40), ("main;a<b>", 50), ("main;a<b>;c", 10)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+
+        let mut svg = Vec::new();
+        render(&mut svg, &folded, &Options::default()).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<rect x=").count(), 3);
+        assert!(svg.contains("<title>main (100 us, 100.00%)</title>"));
+        assert!(svg.contains("a&lt;b&gt; (60 us, 60.00%)"));
+    }
+}
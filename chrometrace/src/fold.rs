@@ -0,0 +1,100 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrometracer::{Span, Thread, Trace};
+
+use crate::stats::exclusive_times;
+
+/// The names of `span` and its ancestors, outermost first, joined by `;`.
+pub fn path(trace: &Trace, span: &Span) -> String {
+    let mut frames: Vec<String> = trace.ancestors(span).map(|s| s.name.replace(';', ":")).collect();
+    frames.reverse();
+    frames.push(span.name.replace(';', ":"));
+    frames.join(";")
+}
+
+/// Collapses nested spans into Brendan Gregg's folded-stack format: each stack path maps to
+/// the self time, in microseconds, spent with exactly that stack. Self times are summed per
+/// stack before being rounded down to microseconds, so many short spans still add up.
+///
+/// With `threads`, every stack starts with a frame naming the thread it ran on.
+pub fn fold(trace: &Trace, threads: bool) -> BTreeMap<String, u64> {
+    let exclusive = exclusive_times(trace);
+    let mut folded = BTreeMap::new();
+
+    for (index, span) in trace.spans.iter().enumerate() {
+        if exclusive[index].is_zero() {
+            continue;
+        }
+
+        let mut stack = String::new();
+        if threads {
+            match trace.threads.iter().find(|t| t.pid == span.pid && t.tid == span.tid) {
+                Some(Thread { name: Some(name), .. }) => stack.push_str(name),
+                _ => stack.push_str(&format!("thread-{}", span.tid)),
+            }
+            stack.push(';');
+        }
+        stack.push_str(&path(trace, span));
+
+        *folded.entry(stack).or_insert(Duration::ZERO) += exclusive[index];
+    }
+
+    folded
+        .into_iter()
+        .map(|(stack, time)| (stack, time.as_micros() as u64))
+        .filter(|&(_, micros)| micros > 0)
+        .collect()
+}
+
+pub fn write<W: Write>(writer: &mut W, folded: &BTreeMap<String, u64>) -> io::Result<()> {
+    for (stack, micros) in folded {
+        writeln!(writer, "{} {}", stack, micros)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacks() {
+        let trace = Trace::read(
+            r#"[
+            {"name":"thread_name","ph":"M","pid":1,"tid":2,"args":{"name":"worker"}},
+            {"name":"main","ph":"X","ts":0,"dur":100,"pid":1,"tid":1},
+            {"name":"a","ph":"X","ts":10,"dur":40,"pid":1,"tid":1},
+            {"name":"b","ph":"X","ts":20,"dur":10,"pid":1,"tid":1},
+            {"name":"a","ph":"X","ts":60,"dur":20,"pid":1,"tid":1},
+            {"name":"a","ph":"X","ts":0,"dur":5,"pid":1,"tid":2}
+        ]"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let folded = fold(&trace, false);
+        assert_eq!(folded["main"], 40);
+        assert_eq!(folded["main;a"], 50);
+        assert_eq!(folded["main;a;b"], 10);
+        assert_eq!(folded["a"], 5);
+
+        let folded = fold(&trace, true);
+        assert_eq!(folded["worker;a"], 5);
+        assert_eq!(folded["thread-1;main;a"], 50);
+    }
+
+    #[test]
+    fn short_spans() {
+        // 1000 spans of 400ns each, too short to count on their own.
+        let events: Vec<_> = (0..1000)
+            .map(|i| format!(r#"{{"name":"tick","ph":"X","ts":{}.0,"dur":0.4,"pid":1,"tid":1}}"#, i))
+            .collect();
+        let trace = Trace::read(format!("[{}]", events.join(",")).as_bytes()).unwrap();
+
+        assert_eq!(fold(&trace, false)["tick"], 400);
+    }
+}
@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+#[cfg(unix)]
+pub mod collector;
+pub mod contention;
+pub mod critical_path;
+pub mod diff;
+pub mod flamegraph;
+pub mod fold;
+pub mod merge;
+pub mod stats;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+pub(crate) fn micros(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1000.0
+}
@@ -0,0 +1,241 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use chrometrace::{contention, critical_path, diff, flamegraph, fold, merge, stats, Format};
+use chrometracer::{reader, Trace};
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(name = "chrometrace", about = "Analyze traces written by chrometracer")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print call count and timing statistics per span name
+    Stats {
+        trace: PathBuf,
+        /// Report each thread separately
+        #[arg(long)]
+        by_thread: bool,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// Write stacks in the folded format used by flame graph tools
+    Fold {
+        trace: PathBuf,
+        /// Start every stack with the thread it ran on
+        #[arg(long)]
+        threads: bool,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Render an SVG flame graph
+    Flamegraph {
+        trace: PathBuf,
+        /// Start every stack with the thread it ran on
+        #[arg(long)]
+        threads: bool,
+        /// Draw the root at the top
+        #[arg(long)]
+        icicle: bool,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Compare two traces and fail when a threshold is exceeded
+    Diff {
+        before: PathBuf,
+        after: PathBuf,
+        /// Match spans by name or by their full stack
+        #[arg(long, value_enum, default_value_t = diff::Key::Name)]
+        by: diff::Key,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+        /// Fail when a mean duration grows significantly, or for spans too rare to test at all,
+        /// by more than this many percent
+        #[arg(long, value_name = "PERCENT")]
+        max_regression: Option<f64>,
+        /// Fail when a call count changes by more than this many percent
+        #[arg(long, value_name = "PERCENT")]
+        max_count_change: Option<f64>,
+    },
+    /// Show the chain of spans, across threads, that determined when a span finished
+    CriticalPath {
+        trace: PathBuf,
+        /// Name of the span to analyze; defaults to the longest top-level span
+        #[arg(long)]
+        root: Option<String>,
+        /// Write a copy of the trace with the critical path highlighted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Summarize how long locks were waited for and held
+    Contention {
+        trace: PathBuf,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// Combine traces of several processes into one timeline
+    Merge {
+        #[arg(required = true)]
+        traces: Vec<PathBuf>,
+        /// Clock to line the traces up on
+        #[arg(long, value_enum, default_value_t = merge::Clock::Monotonic)]
+        clock: merge::Clock,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Send a request to the control socket of a running process: start, stop, dump or stats
+    #[cfg(unix)]
+    Ctl {
+        socket: PathBuf,
+        /// The request and its options, such as `start --filter db --duration 10`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        request: Vec<String>,
+    },
+}
+
+/// Where a command writes its result. Callers flush it, so that errors writing a file are
+/// reported rather than lost when the buffer is dropped.
+fn output(path: Option<PathBuf>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    })
+}
+
+fn run(cli: Cli) -> io::Result<ExitCode> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match cli.command {
+        Command::Stats {
+            trace,
+            by_thread,
+            format,
+        } => {
+            let trace = Trace::open(trace)?;
+            stats::write(&mut out, &stats::compute(&trace, by_thread), format)?;
+        }
+        Command::Fold {
+            trace,
+            threads,
+            output: path,
+        } => {
+            let trace = Trace::open(trace)?;
+            let mut output = output(path)?;
+            fold::write(&mut output, &fold::fold(&trace, threads))?;
+            output.flush()?;
+        }
+        Command::Flamegraph {
+            trace,
+            threads,
+            icicle,
+            title,
+            output: path,
+        } => {
+            let trace = Trace::open(trace)?;
+            let options = flamegraph::Options {
+                title: title.unwrap_or_else(|| "Flame Graph".to_string()),
+                icicle,
+                ..Default::default()
+            };
+            let mut output = output(path)?;
+            flamegraph::render(&mut output, &fold::fold(&trace, threads), &options)?;
+            output.flush()?;
+        }
+        Command::Diff {
+            before,
+            after,
+            by,
+            format,
+            max_regression,
+            max_count_change,
+        } => {
+            let thresholds = diff::Thresholds {
+                max_regression,
+                max_count_change,
+            };
+            let deltas = diff::compare(&Trace::open(before)?, &Trace::open(after)?, by, &thresholds);
+            diff::write(&mut out, &deltas, format)?;
+
+            if deltas.iter().any(|d| d.exceeded) {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::CriticalPath {
+            trace,
+            root,
+            output: path,
+        } => {
+            let events = reader::open(trace)?.collect::<io::Result<Vec<_>>>()?;
+            let values = match path {
+                Some(_) => events.iter().map(reader::event_to_value).collect::<Result<Vec<_>, _>>()?,
+                None => Vec::new(),
+            };
+            let trace = Trace::from_events(events);
+
+            let root = critical_path::find_root(&trace, root.as_deref())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such span in the trace"))?;
+            let segments = critical_path::critical_path(&trace, root);
+            critical_path::write_summary(&mut out, &trace, root, &critical_path::summarize(&trace, &segments))?;
+
+            if path.is_some() {
+                let mut output = output(path)?;
+                critical_path::annotate(&mut output, &values, &trace, &segments)?;
+                output.flush()?;
+            }
+        }
+        Command::Contention { trace, format } => {
+            let trace = Trace::open(trace)?;
+            contention::write(&mut out, &contention::compute(&trace), format)?;
+        }
+        Command::Merge {
+            traces,
+            clock,
+            output: path,
+        } => {
+            let inputs = traces
+                .iter()
+                .map(|trace| {
+                    let events = reader::open(trace)?
+                        .map(|event| Ok(reader::event_to_value(&event?)?))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    let label = trace.file_stem().unwrap_or(trace.as_os_str()).to_string_lossy().into_owned();
+                    Ok(merge::Input { label, events })
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            let mut output = output(path)?;
+            merge::write(&mut output, &merge::merge(inputs, clock)?)?;
+            output.flush()?;
+        }
+        #[cfg(unix)]
+        Command::Ctl { socket, request } => {
+            let reply = chrometracer::control::request(socket, &request.join(" "))?;
+            writeln!(out, "{}", reply)?;
+            if reply.starts_with("error") {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("chrometrace: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+use serde_json::{json, Value};
+
+/// Clock used to line up traces, as recorded by the `clock_anchor` metadata event that
+/// chrometracer writes at the start of every trace.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Clock {
+    /// Steady, but only comparable between processes of the same boot of one machine.
+    #[default]
+    Monotonic,
+    /// Wall clock time, for traces from several machines with synchronized clocks.
+    Realtime,
+}
+
+impl Clock {
+    fn arg(self) -> &'static str {
+        match self {
+            Clock::Monotonic => "monotonic_ns",
+            Clock::Realtime => "realtime_ns",
+        }
+    }
+}
+
+/// The events of one trace to merge, and the name to give its processes if they have none.
+pub struct Input {
+    pub label: String,
+    pub events: Vec<Value>,
+}
+
+/// Time in nanoseconds on `clock` at which the timestamps of `events` start.
+pub fn anchor(events: &[Value], clock: Clock) -> Option<u128> {
+    let anchor = events.iter().find(|e| e["ph"] == "M" && e["name"] == "clock_anchor")?;
+    match &anchor["args"][clock.arg()] {
+        Value::String(ns) => ns.parse().ok(),
+        Value::Number(ns) => ns.as_u64().map(u128::from),
+        _ => None,
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Combines several traces into one timeline.
+///
+/// Timestamps are shifted so each trace starts at its anchor relative to the earliest one. Pids
+/// already taken by an earlier input are renumbered, flow ids are made distinct per input, and
+/// processes without a `process_name` are named after the label of their input.
+pub fn merge(inputs: Vec<Input>, clock: Clock) -> io::Result<Vec<Value>> {
+    let anchors = inputs
+        .iter()
+        .map(|input| {
+            anchor(&input.events, clock)
+                .ok_or_else(|| invalid(format!("{}: no {} clock anchor in the trace", input.label, clock.arg())))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let base = anchors.iter().copied().min().unwrap_or_default();
+
+    let pid = |event: &Value| event["pid"].as_u64().unwrap_or_default();
+    let mut taken: BTreeSet<u64> = inputs.iter().flat_map(|input| input.events.iter().map(pid)).collect();
+    let mut used = BTreeSet::new();
+    let mut merged = Vec::new();
+
+    for (index, (input, anchor)) in inputs.into_iter().zip(anchors).enumerate() {
+        let offset = (anchor - base) as f64 / 1000.0;
+
+        let mut pids = HashMap::new();
+        for pid in input.events.iter().map(pid).collect::<BTreeSet<_>>() {
+            let renumbered = if used.contains(&pid) {
+                let free = (1..).find(|p| !taken.contains(p)).unwrap();
+                taken.insert(free);
+                free
+            } else {
+                pid
+            };
+            used.insert(renumbered);
+            pids.insert(pid, renumbered);
+        }
+
+        let mut named = BTreeMap::new();
+        for mut event in input.events {
+            let renumbered = pids[&pid(&event)];
+            event["pid"] = json!(renumbered);
+
+            match event["ph"].as_str() {
+                Some("M") => {
+                    if event["name"] == "process_name" {
+                        named.insert(renumbered, true);
+                    }
+                }
+                _ => {
+                    if let Some(ts) = event["ts"].as_f64() {
+                        // Rounded to the nanosecond, as traces are written.
+                        event["ts"] = json!(((ts + offset) * 1000.0).round() / 1000.0);
+                    }
+                }
+            }
+            if matches!(event["ph"].as_str(), Some("s" | "t" | "f")) {
+                let id = match &event["id"] {
+                    Value::String(id) => id.clone(),
+                    id => id.to_string(),
+                };
+                event["id"] = json!(format!("{}.{}", index, id));
+            }
+            named.entry(renumbered).or_insert(false);
+            merged.push(event);
+        }
+
+        for (pid, _) in named.into_iter().filter(|(_, named)| !named) {
+            merged.push(json!({
+                "name": "process_name",
+                "ph": "M",
+                "ts": 0,
+                "pid": pid,
+                "tid": 0,
+                "args": { "name": input.label },
+            }));
+        }
+    }
+
+    Ok(merged)
+}
+
+pub fn write<W: Write>(writer: &mut W, events: &[Value]) -> io::Result<()> {
+    writer.write_all(b"[\n")?;
+    for (i, event) in events.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",\n")?;
+        }
+        serde_json::to_writer(&mut *writer, event)?;
+    }
+    writer.write_all(b"\n]\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrometracer::Trace;
+
+    use super::*;
+
+    fn input(label: &str, monotonic_ns: u64, events: &[Value]) -> Input {
+        let anchor = json!({"name":"clock_anchor","ph":"M","ts":0,"pid":1,"tid":1,"args":{"monotonic_ns":monotonic_ns.to_string()}});
+        Input {
+            label: label.to_string(),
+            events: std::iter::once(anchor).chain(events.iter().cloned()).collect(),
+        }
+    }
+
+    #[test]
+    fn aligned() {
+        let parent = input(
+            "parent",
+            1_000_000,
+            &[
+                json!({"name":"main","ph":"X","ts":0,"dur":100,"pid":1,"tid":1}),
+                json!({"name":"flow","cat":"flow","ph":"s","id":1,"ts":0,"pid":1,"tid":1}),
+            ],
+        );
+        let child = input(
+            "child",
+            1_050_000,
+            &[
+                json!({"name":"work","ph":"X","ts":10,"dur":20,"pid":1,"tid":1}),
+                json!({"name":"flow","cat":"flow","ph":"s","id":1,"ts":10,"pid":1,"tid":1}),
+            ],
+        );
+
+        let mut output = Vec::new();
+        write(&mut output, &merge(vec![parent, child], Clock::Monotonic).unwrap()).unwrap();
+        let trace = Trace::read(output.as_slice()).unwrap();
+
+        let work = trace.span("work").unwrap();
+        assert_ne!(work.pid, trace.span("main").unwrap().pid);
+        assert_eq!(work.from.as_micros(), 60);
+        assert_eq!(trace.flows.len(), 2);
+        assert!(trace.threads.iter().any(|t| t.pid == work.pid));
+    }
+
+    #[test]
+    fn unanchored() {
+        let trace = Input {
+            label: "old".to_string(),
+            events: vec![json!({"name":"main","ph":"X","ts":0,"dur":1,"pid":1,"tid":1})],
+        };
+        assert!(merge(vec![trace], Clock::Monotonic).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::Duration,
+};
+
+use chrometracer::Trace;
+use serde::Serialize;
+
+use crate::{micros, Format};
+
+/// Timing summary of all spans sharing a name (and thread, when grouped by thread).
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    pub name: String,
+    /// Process of the thread, since thread ids are only unique within a process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tid: Option<u64>,
+    pub count: usize,
+    /// Sum of all durations.
+    #[serde(serialize_with = "as_micros")]
+    pub total: Duration,
+    /// Like `total`, but recursive calls are only counted once.
+    #[serde(serialize_with = "as_micros")]
+    pub inclusive: Duration,
+    /// Time not spent in nested spans.
+    #[serde(rename = "self", serialize_with = "as_micros")]
+    pub exclusive: Duration,
+    #[serde(serialize_with = "as_micros")]
+    pub min: Duration,
+    #[serde(serialize_with = "as_micros")]
+    pub mean: Duration,
+    #[serde(serialize_with = "as_micros")]
+    pub p50: Duration,
+    #[serde(serialize_with = "as_micros")]
+    pub p95: Duration,
+    #[serde(serialize_with = "as_micros")]
+    pub p99: Duration,
+    #[serde(serialize_with = "as_micros")]
+    pub max: Duration,
+}
+
+fn as_micros<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(micros(*duration))
+}
+
+/// Nearest-rank percentile of sorted `durations`.
+pub fn percentile(durations: &[Duration], p: f64) -> Duration {
+    if durations.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * durations.len() as f64).ceil() as usize;
+    durations[rank.clamp(1, durations.len()) - 1]
+}
+
+/// Time of each span not covered by its children.
+pub fn exclusive_times(trace: &Trace) -> Vec<Duration> {
+    let mut times: Vec<Duration> = trace.spans.iter().map(|s| s.duration()).collect();
+    for span in &trace.spans {
+        if let Some(parent) = span.parent {
+            times[parent] = times[parent].saturating_sub(span.duration());
+        }
+    }
+    times
+}
+
+pub fn compute(trace: &Trace, by_thread: bool) -> Vec<Stats> {
+    let exclusive = exclusive_times(trace);
+
+    #[derive(Default)]
+    struct Group {
+        durations: Vec<Duration>,
+        inclusive: Duration,
+        exclusive: Duration,
+    }
+
+    /// A pid and tid, when grouping by thread.
+    type Thread = Option<(u64, u64)>;
+
+    let mut groups: BTreeMap<(&str, Thread), Group> = BTreeMap::new();
+    for (index, span) in trace.spans.iter().enumerate() {
+        let group = groups
+            .entry((span.name.as_str(), by_thread.then_some((span.pid, span.tid))))
+            .or_default();
+
+        group.durations.push(span.duration());
+        group.exclusive += exclusive[index];
+        if !trace.ancestors(span).any(|a| a.name == span.name) {
+            group.inclusive += span.duration();
+        }
+    }
+
+    let mut stats: Vec<Stats> = groups
+        .into_iter()
+        .map(|((name, thread), group)| {
+            let Group {
+                mut durations,
+                inclusive,
+                exclusive,
+            } = group;
+            durations.sort();
+            let total: Duration = durations.iter().sum();
+            Stats {
+                name: name.to_string(),
+                pid: thread.map(|(pid, _)| pid),
+                tid: thread.map(|(_, tid)| tid),
+                count: durations.len(),
+                total,
+                inclusive,
+                exclusive,
+                min: durations[0],
+                mean: total / durations.len() as u32,
+                p50: percentile(&durations, 50.0),
+                p95: percentile(&durations, 95.0),
+                p99: percentile(&durations, 99.0),
+                max: durations[durations.len() - 1],
+            }
+        })
+        .collect();
+
+    stats.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then_with(|| a.name.cmp(&b.name)));
+    stats
+}
+
+const COLUMNS: [&str; 9] = ["total", "inclusive", "self", "min", "mean", "p50", "p95", "p99", "max"];
+
+fn times(stats: &Stats) -> [Duration; 9] {
+    [
+        stats.total,
+        stats.inclusive,
+        stats.exclusive,
+        stats.min,
+        stats.mean,
+        stats.p50,
+        stats.p95,
+        stats.p99,
+        stats.max,
+    ]
+}
+
+pub fn write<W: Write>(writer: &mut W, stats: &[Stats], format: Format) -> io::Result<()> {
+    let by_thread = stats.iter().any(|s| s.tid.is_some());
+
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *writer, stats)?;
+            writeln!(writer)
+        }
+        Format::Csv => {
+            write!(writer, "name,")?;
+            if by_thread {
+                write!(writer, "pid,tid,")?;
+            }
+            writeln!(writer, "count,{}", COLUMNS.iter().map(|c| format!("{}_us", c)).collect::<Vec<_>>().join(","))?;
+
+            for s in stats {
+                write!(writer, "\"{}\",", s.name.replace('"', "\"\""))?;
+                if let (Some(pid), Some(tid)) = (s.pid, s.tid) {
+                    write!(writer, "{},{},", pid, tid)?;
+                }
+                let times: Vec<String> = times(s).iter().map(|t| format!("{:.3}", micros(*t))).collect();
+                writeln!(writer, "{},{}", s.count, times.join(","))?;
+            }
+            Ok(())
+        }
+        Format::Table => {
+            let width = stats.iter().map(|s| s.name.len()).max().unwrap_or(0).max(4);
+
+            write!(writer, "{:<width$}", "name", width = width)?;
+            if by_thread {
+                write!(writer, " {:>8} {:>8}", "pid", "tid")?;
+            }
+            write!(writer, " {:>8}", "count")?;
+            for column in COLUMNS {
+                write!(writer, " {:>13}", format!("{}(us)", column))?;
+            }
+            writeln!(writer)?;
+
+            for s in stats {
+                write!(writer, "{:<width$}", s.name, width = width)?;
+                if let (Some(pid), Some(tid)) = (s.pid, s.tid) {
+                    write!(writer, " {:>8} {:>8}", pid, tid)?;
+                }
+                write!(writer, " {:>8}", s.count)?;
+                for time in times(s) {
+                    write!(writer, " {:>13.3}", micros(time))?;
+                }
+                writeln!(writer)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = r#"[
+        {"name":"main","ph":"X","ts":0,"dur":100,"pid":1,"tid":1},
+        {"name":"work","ph":"X","ts":10,"dur":40,"pid":1,"tid":1},
+        {"name":"work","ph":"X","ts":20,"dur":10,"pid":1,"tid":1},
+        {"name":"work","ph":"X","ts":60,"dur":20,"pid":1,"tid":1},
+        {"name":"work","ph":"X","ts":0,"dur":30,"pid":1,"tid":2}
+    ]"#;
+
+    #[test]
+    fn summary() {
+        let trace = Trace::read(TRACE.as_bytes()).unwrap();
+        let stats = compute(&trace, false);
+
+        let main = &stats[0];
+        assert_eq!(main.name, "main");
+        assert_eq!(main.exclusive, Duration::from_micros(40));
+
+        let work = &stats[1];
+        assert_eq!(work.count, 4);
+        assert_eq!(work.total, Duration::from_micros(100));
+        assert_eq!(work.inclusive, Duration::from_micros(90));
+        assert_eq!(work.exclusive, Duration::from_micros(90));
+        assert_eq!((work.min, work.p50, work.max), (Duration::from_micros(10), Duration::from_micros(20), Duration::from_micros(40)));
+    }
+
+    #[test]
+    fn by_thread() {
+        // The same thread id in another process is another thread.
+        let events = TRACE.replace("\n    ]", r#",{"name":"work","ph":"X","ts":0,"dur":5,"pid":2,"tid":2}]"#);
+        let trace = Trace::read(events.as_bytes()).unwrap();
+        let stats = compute(&trace, true);
+
+        assert_eq!(stats.len(), 4);
+        assert!(stats.iter().any(|s| s.name == "work" && (s.pid, s.tid) == (Some(1), Some(2)) && s.count == 1));
+        assert!(stats.iter().any(|s| s.name == "work" && (s.pid, s.tid) == (Some(2), Some(2)) && s.count == 1));
+    }
+
+    #[test]
+    fn formats() {
+        let trace = Trace::read(TRACE.as_bytes()).unwrap();
+        let stats = compute(&trace, false);
+
+        let mut csv = Vec::new();
+        write(&mut csv, &stats, Format::Csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("name,count,total_us,"));
+        assert!(csv.contains("\"work\",4,100.000,90.000,90.000,10.000,25.000,20.000,40.000,40.000"));
+
+        let mut json = Vec::new();
+        write(&mut json, &stats, Format::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[1]["self"], 90.0);
+    }
+}
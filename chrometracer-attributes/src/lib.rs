@@ -1,7 +1,8 @@
 use std::collections::HashSet;
 
 use proc_macro::TokenStream;
-use quote::ToTokens;
+use proc_macro2::Span;
+use quote::{quote, ToTokens};
 use syn::ext::IdentExt;
 use syn::{
     parse::{Parse, ParseStream, Parser},
@@ -17,6 +18,8 @@ struct ChromeEventArgs {
     event: Option<Event>,
     fields: Fields,
     skips: HashSet<Ident>,
+    follows_from: Option<Expr>,
+    min_duration: Option<LitStr>,
 }
 
 #[derive(Default)]
@@ -45,6 +48,38 @@ enum Level {
     Path(Path),
 }
 
+impl Level {
+    fn to_level(&self) -> syn::Result<proc_macro2::TokenStream> {
+        let from_name = |name: &str, span| match name.to_ascii_lowercase().as_str() {
+            "error" => Ok(quote!(chrometracer::Level::Error)),
+            "warn" => Ok(quote!(chrometracer::Level::Warn)),
+            "info" => Ok(quote!(chrometracer::Level::Info)),
+            "debug" => Ok(quote!(chrometracer::Level::Debug)),
+            "trace" => Ok(quote!(chrometracer::Level::Trace)),
+            _ => Err(syn::Error::new(
+                span,
+                "unknown level, expected one of \"error\", \"warn\", \"info\", \"debug\", \"trace\"",
+            )),
+        };
+
+        match self {
+            Level::Str(lit) => from_name(&lit.value(), lit.span()),
+            Level::Int(lit) => match lit.base10_parse::<u8>()? {
+                1 => Ok(quote!(chrometracer::Level::Error)),
+                2 => Ok(quote!(chrometracer::Level::Warn)),
+                3 => Ok(quote!(chrometracer::Level::Info)),
+                4 => Ok(quote!(chrometracer::Level::Debug)),
+                5 => Ok(quote!(chrometracer::Level::Trace)),
+                _ => Err(syn::Error::new(lit.span(), "level must be between 1 and 5")),
+            },
+            Level::Path(path) => match path.get_ident() {
+                Some(ident) => from_name(&ident.to_string(), ident.span()),
+                None => Ok(path.to_token_stream()),
+            },
+        }
+    }
+}
+
 impl Parse for Level {
     fn parse(input: ParseStream<'_>) -> syn::Result<Self> {
         let _ = input.parse::<kw::level>()?;
@@ -109,11 +144,18 @@ impl Parse for ChromeEventArgs {
             } else if lookahead.peek(kw::target) {
                 let target = input.parse::<StrArg<kw::target>>()?.value;
                 args.target = Some(target);
+            } else if lookahead.peek(kw::follows_from) {
+                let _ = input.parse::<kw::follows_from>()?;
+                let _ = input.parse::<Token![=]>()?;
+                args.follows_from = Some(input.parse()?);
+            } else if lookahead.peek(kw::min_duration) {
+                let min_duration = input.parse::<StrArg<kw::min_duration>>()?.value;
+                args.min_duration = Some(min_duration);
             } else if lookahead.peek(Token![,]) {
                 let _ = input.parse::<Token![,]>()?;
             } else {
                 panic!(
-                    "Unknown fields, expected one of \"event\", \"level\", \"fields\", \"skip\", \"target\"",
+                    "Unknown fields, expected one of \"event\", \"level\", \"fields\", \"skip\", \"target\", \"follows_from\", \"min_duration\"",
                 )
             }
         }
@@ -138,6 +180,25 @@ impl<T: Parse> Parse for StrArg<T> {
     }
 }
 
+/// Parses a duration such as `"50us"` into nanoseconds.
+fn parse_duration(lit: &LitStr) -> syn::Result<u64> {
+    let value = lit.value();
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale = match unit.trim() {
+        "ns" => 1.0,
+        "us" | "µs" => 1e3,
+        "ms" => 1e6,
+        "s" => 1e9,
+        _ => return Err(syn::Error::new(lit.span(), "expected a duration such as \"50us\", in ns, us, ms or s")),
+    };
+    match number.parse::<f64>() {
+        Ok(number) => Ok((number * scale).round() as u64),
+        Err(_) => Err(syn::Error::new(lit.span(), "expected a duration such as \"50us\", in ns, us, ms or s")),
+    }
+}
+
 struct Event {
     keyword: kw::event,
     colon_token: Token![:],
@@ -190,7 +251,7 @@ impl ToTokens for Field {
 }
 
 #[proc_macro_attribute]
-pub fn instrument(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
     //println!("{:?}", attr);
     //println!("{:?}", item);
 
@@ -209,15 +270,88 @@ pub fn instrument(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
 
 
+    if cfg!(feature = "off") {
+        return item;
+    }
+
+    let args = syn::parse_macro_input!(attr as ChromeEventArgs);
+
+    let level = match args.level.as_ref().map(Level::to_level) {
+        Some(Ok(level)) => level,
+        Some(Err(e)) => return e.to_compile_error().into(),
+        None => quote!(chrometracer::Level::Info),
+    };
+
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    let mut locals = Vec::new();
+    for (i, field) in args.fields.0.iter().enumerate() {
+        match field.path.get_ident() {
+            Some(ident) => keys.push(ident.clone()),
+            None => {
+                return syn::Error::new_spanned(&field.path, "field name must be an identifier")
+                    .to_compile_error()
+                    .into()
+            }
+        }
+        values.push(&field.value);
+        locals.push(Ident::new(&format!("__chrometracer_field_{}", i), Span::call_site()));
+    }
+
+    // Ends the flow inside the new span, so the arrow points at it.
+    let (follows_from, flow_end) = match &args.follows_from {
+        Some(id) => (
+            Some(quote!(let __chrometracer_follows_from: u64 = #id;)),
+            Some(quote!(chrometracer::flow_end(__chrometracer_follows_from);)),
+        ),
+        None => (None, None),
+    };
+
+    // Spans shorter than `min_duration` are not recorded; their children still are. Such a span
+    // is not entered, so it hands out no id its children could name as a parent that never
+    // appears; they record the enclosing span as their parent instead.
+    let min_duration = match args.min_duration.as_ref().map(parse_duration) {
+        Some(Ok(nanos)) => Some(quote!(::std::time::Duration::from_nanos(#nanos))),
+        Some(Err(e)) => return e.to_compile_error().into(),
+        None => None,
+    };
+
     let mut input = syn::Item::parse.parse(item).unwrap();
 
     if let syn::Item::Fn(ref mut item) = input {
         let original = &item.block;
         let name = &item.sig.ident;
-        let is_async = *&item.sig.asyncness.is_some();
+        let is_async = item.sig.asyncness.is_some();
+        // An async body may be suspended while other spans run on the thread, so it does not
+        // become their parent.
+        let enter = if is_async {
+            quote!(chrometracer::enter_detached())
+        } else {
+            quote!(chrometracer::enter())
+        };
+        let (enter, record) = match &min_duration {
+            Some(min_duration) => (
+                None,
+                quote! {
+                    if to - from >= #min_duration {
+                        chrometracer::event!(level: #level, name: stringify!(#name), from: from, to: to, is_async: #is_async #(, #keys = #locals)*);
+                    }
+                },
+            ),
+            None => (
+                Some(quote!(let __chrometracer_span = #enter;)),
+                quote! {
+                    chrometracer::event!(level: #level, name: stringify!(#name), from: from, to: to, is_async: #is_async, span: &__chrometracer_span #(, #keys = #locals)*);
+                },
+            ),
+        };
         //println!("{}", name);
-        item.block = Box::new(parse_quote! {{
-            let start = chrometracer::current(|tracer| tracer.map(|t| t.start));
+        *item.block = parse_quote! {{
+            let start = if chrometracer::enabled(#level) {
+                chrometracer::current(|tracer| tracer.map(|t| t.start))
+            } else {
+                None
+            };
 
             if let Some(start) = start {
                 // let event = match #event {
@@ -236,13 +370,17 @@ pub fn instrument(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 // } else {
                     // let now = ::std::time::SystemTime::now();
                     // let ts = now.duration_since(start).unwrap().as_nanos() as f64 / 1000.0;
+                    #(let #locals = #values;)*
+                    #follows_from
+                    #enter
                     let from = start.elapsed();
+                    #flow_end
                     let ret = #original;
                     let to = start.elapsed();
                     // let dur = ::std::time::SystemTime::now().duration_since(now).unwrap().as_nanos() as f64 / 1000.0;
                     
                     //chrometracer::event!(name: name, #(#fields3,)* ph = chrometracer::EventType::Complete, dur = dur, ts = ts);
-                    chrometracer::event!(name: stringify!(#name), from: from, to: to, is_async: #is_async);
+                    #record
                     // ret
                 // };
 
@@ -250,7 +388,7 @@ pub fn instrument(_attr: TokenStream, item: TokenStream) -> TokenStream {
             } else {
                 #original
             }
-        }});
+        }};
     } else {
         unreachable!()
     }
@@ -268,4 +406,6 @@ mod kw {
     syn::custom_keyword!(fields);
     syn::custom_keyword!(level);
     syn::custom_keyword!(target);
+    syn::custom_keyword!(follows_from);
+    syn::custom_keyword!(min_duration);
 }
\ No newline at end of file
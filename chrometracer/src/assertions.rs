@@ -0,0 +1,164 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::trace::{Span, Trace};
+
+/// Checks used by [`assert_span!`](crate::assert_span). Each returns a description of the
+/// first violation found.
+impl Trace {
+    /// Every span called `outer` has at least one `inner` nested inside it.
+    pub fn check_contains(&self, outer: &str, inner: &str) -> Result<(), String> {
+        let outers = self.existing(outer)?;
+        for span in outers {
+            if !self.spans_named(inner).any(|s| self.contains(span, s)) {
+                return Err(format!(
+                    "\"{}\" at {:?} on thread {} does not contain \"{}\"",
+                    outer, span.from, span.tid, inner
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_count(&self, name: &str, count: usize) -> Result<(), String> {
+        let actual = self.spans_named(name).count();
+        if actual == count {
+            Ok(())
+        } else {
+            Err(format!("\"{}\" was called {} times, expected {}", name, actual, count))
+        }
+    }
+
+    /// No two spans called `name` overlap in time on the same thread.
+    pub fn check_no_overlap(&self, name: &str) -> Result<(), String> {
+        let mut last: HashMap<(u64, u64), &Span> = HashMap::new();
+        for span in self.spans_named(name) {
+            if let Some(previous) = last.insert((span.pid, span.tid), span) {
+                if span.from < previous.to {
+                    return Err(format!(
+                        "\"{}\" overlaps on thread {}: {:?}..{:?} and {:?}..{:?}",
+                        name, span.tid, previous.from, previous.to, span.from, span.to
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_max_duration(&self, name: &str, max: Duration) -> Result<(), String> {
+        for span in self.existing(name)? {
+            if span.duration() > max {
+                return Err(format!(
+                    "\"{}\" at {:?} took {:?}, expected at most {:?}",
+                    name,
+                    span.from,
+                    span.duration(),
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The first span called `first` finishes before any span called `second` starts.
+    pub fn check_before(&self, first: &str, second: &str) -> Result<(), String> {
+        let end = self.existing(first)?.map(|s| s.to).min().unwrap();
+        let start = self.existing(second)?.map(|s| s.from).min().unwrap();
+        if end <= start {
+            Ok(())
+        } else {
+            Err(format!(
+                "\"{}\" started at {:?}, before \"{}\" finished at {:?}",
+                second, start, first, end
+            ))
+        }
+    }
+
+    fn existing<'a>(&'a self, name: &'a str) -> Result<impl Iterator<Item = &'a Span> + 'a, String> {
+        if self.span(name).is_some() {
+            Ok(self.spans_named(name))
+        } else {
+            Err(format!("no span named \"{}\" was recorded", name))
+        }
+    }
+}
+
+/// Asserts a property of the spans in a [`Trace`](crate::Trace).
+///
+/// ```ignore
+/// assert_span!(trace, "pipeline" contains "decode");
+/// assert_span!(trace, "decode" called 4 times);
+/// assert_span!(trace, "decode" never overlaps);
+/// assert_span!(trace, "decode" under Duration::from_millis(5));
+/// assert_span!(trace, "load" before "decode");
+/// ```
+#[macro_export]
+macro_rules! assert_span {
+    (@check $result:expr) => {
+        if let Err(message) = $result {
+            panic!("{}", message);
+        }
+    };
+    ($trace:expr, $outer:literal contains $inner:literal) => {
+        $crate::assert_span!(@check $trace.check_contains($outer, $inner))
+    };
+    ($trace:expr, $name:literal called $count:tt times) => {
+        $crate::assert_span!(@check $trace.check_count($name, $count))
+    };
+    ($trace:expr, $name:literal never overlaps) => {
+        $crate::assert_span!(@check $trace.check_no_overlap($name))
+    };
+    ($trace:expr, $name:literal under $max:expr) => {
+        $crate::assert_span!(@check $trace.check_max_duration($name, $max))
+    };
+    ($trace:expr, $first:literal before $second:literal) => {
+        $crate::assert_span!(@check $trace.check_before($first, $second))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[crate::instrument]
+    fn load() {}
+
+    #[crate::instrument]
+    fn decode() {}
+
+    #[crate::instrument]
+    fn pipeline() {
+        load();
+        decode();
+        decode();
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn pipeline_structure() {
+        let trace = crate::capture(pipeline);
+
+        assert_span!(trace, "pipeline" contains "decode");
+        assert_span!(trace, "decode" called 2 times);
+        assert_span!(trace, "decode" never overlaps);
+        assert_span!(trace, "pipeline" under Duration::from_secs(1));
+        assert_span!(trace, "load" before "decode");
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    #[should_panic(expected = "\"decode\" was called 2 times, expected 3")]
+    fn wrong_count() {
+        let trace = crate::capture(pipeline);
+
+        assert_span!(trace, "decode" called 3 times);
+    }
+
+    #[test]
+    fn violations() {
+        let trace = crate::capture(pipeline);
+
+        assert!(trace.check_contains("decode", "pipeline").is_err());
+        assert!(trace.check_before("decode", "load").is_err());
+        assert!(trace.check_max_duration("missing", Duration::ZERO).is_err());
+    }
+}
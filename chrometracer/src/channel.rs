@@ -0,0 +1,439 @@
+//! Channels that draw an arrow from every `send` to the `recv` that got the message.
+//!
+//! Sending and receiving are recorded as short `send` and `recv` spans, categorized by the
+//! channel name, and linked by a flow. The `recv` span carries the time the message spent
+//! queued as `latency_us`. With [`Builder::queue_depth`], the number of queued messages is
+//! also recorded as a counter named after the channel.
+
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub use crossbeam_channel::{RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError};
+use tracing_chrometrace::EventType;
+
+use crate::{current, enter_detached, flow_id, tracer::start_if, Level, SimpleEvent};
+
+struct Envelope<T> {
+    value: T,
+    tag: Option<Tag>,
+}
+
+#[derive(Clone, Copy)]
+struct Tag {
+    flow: u64,
+    sent: Duration,
+}
+
+#[derive(Clone)]
+struct Meta {
+    name: &'static str,
+    depth: Option<Arc<AtomicI64>>,
+}
+
+impl Meta {
+    fn change_depth(&self, delta: i64) -> Option<i64> {
+        self.depth.as_ref().map(|depth| depth.fetch_add(delta, Ordering::Relaxed) + delta)
+    }
+
+    fn record(&self, name: &'static str, span: Range<Duration>, args: Vec<(&'static str, String)>, ph: EventType, tag: Tag, depth: Option<i64>) {
+        let Range { start: from, end: to } = span;
+        let at = if ph == EventType::FlowStart { from } else { to };
+        current(|tracer| {
+            if let Some(tracer) = tracer {
+                let event = |name, cat, ph, id, args| SimpleEvent {
+                    name,
+                    cat,
+                    ph,
+                    from: at,
+                    to: at,
+                    id,
+                    tid: tracer.tid,
+                    args,
+                };
+
+                tracer.trace(SimpleEvent {
+                    from,
+                    to,
+                    ..event(name, self.name, EventType::Complete, 0, args.clone())
+                });
+                tracer.trace(event("message", "channel", ph, tag.flow, Vec::new()));
+                if let Some(depth) = depth {
+                    tracer.trace(event(self.name, "channel", EventType::Counter, 0, vec![("depth", depth.to_string())]));
+                }
+            }
+        })
+    }
+
+    fn send<T, E>(&self, value: T, send: impl FnOnce(Envelope<T>) -> Result<(), E>) -> Result<(), E> {
+        let start = start_if(Level::Info);
+        let span = start.map(|_| enter_detached());
+        let from = start.map(|s| s.elapsed());
+        let tag = from.map(|sent| Tag { flow: flow_id(), sent });
+
+        // Counted before sending, so the receiver never sees a negative depth.
+        let depth = self.change_depth(1);
+        if let Err(e) = send(Envelope { value, tag }) {
+            self.change_depth(-1);
+            return Err(e);
+        }
+
+        if let (Some(start), Some(from), Some(tag), Some(span)) = (start, from, tag, span) {
+            self.record("send", from..start.elapsed(), span.args(), EventType::FlowStart, tag, depth);
+        }
+        Ok(())
+    }
+
+    fn recv<T, E>(&self, recv: impl FnOnce() -> Result<Envelope<T>, E>) -> Result<T, E> {
+        let start = start_if(Level::Info);
+        let span = start.map(|_| enter_detached());
+        let from = start.map(|s| s.elapsed());
+        let envelope = recv()?;
+        let depth = self.change_depth(-1);
+
+        if let (Some(start), Some(from), Some(tag), Some(span)) = (start, from, envelope.tag, span) {
+            let to = start.elapsed();
+            let latency = to.saturating_sub(tag.sent).as_nanos() as f64 / 1000.0;
+            let mut args = span.args();
+            args.push(("latency_us", latency.to_string()));
+            self.record("recv", from..to, args, EventType::FlowEnd, tag, depth);
+        }
+        Ok(envelope.value)
+    }
+}
+
+/// Configures the name and counters of a traced channel.
+#[derive(Clone, Debug)]
+pub struct Builder {
+    name: &'static str,
+    queue_depth: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            name: "channel",
+            queue_depth: false,
+        }
+    }
+}
+
+pub fn builder() -> Builder {
+    Builder::default()
+}
+
+impl Builder {
+    /// Category of the `send` and `recv` spans, and name of the queue-depth counter.
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Records the number of queued messages as a counter.
+    pub fn queue_depth(mut self, queue_depth: bool) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    fn meta(&self) -> Meta {
+        Meta {
+            name: self.name,
+            depth: self.queue_depth.then(|| Arc::new(AtomicI64::new(0))),
+        }
+    }
+
+    fn wrap<T>((sender, receiver): (crossbeam_channel::Sender<Envelope<T>>, crossbeam_channel::Receiver<Envelope<T>>), meta: Meta) -> (Sender<T>, Receiver<T>) {
+        (
+            Sender {
+                inner: sender,
+                meta: meta.clone(),
+            },
+            Receiver { inner: receiver, meta },
+        )
+    }
+
+    pub fn bounded<T>(self, cap: usize) -> (Sender<T>, Receiver<T>) {
+        Self::wrap(crossbeam_channel::bounded(cap), self.meta())
+    }
+
+    pub fn unbounded<T>(self) -> (Sender<T>, Receiver<T>) {
+        Self::wrap(crossbeam_channel::unbounded(), self.meta())
+    }
+
+    /// Like [`std::sync::mpsc::channel`].
+    pub fn mpsc<T>(self) -> (mpsc::Sender<T>, mpsc::Receiver<T>) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let meta = self.meta();
+        (
+            mpsc::Sender {
+                inner: sender,
+                meta: meta.clone(),
+            },
+            mpsc::Receiver { inner: receiver, meta },
+        )
+    }
+
+    /// Like [`std::sync::mpsc::sync_channel`].
+    pub fn sync_mpsc<T>(self, bound: usize) -> (mpsc::SyncSender<T>, mpsc::Receiver<T>) {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(bound);
+        let meta = self.meta();
+        (
+            mpsc::SyncSender {
+                inner: sender,
+                meta: meta.clone(),
+            },
+            mpsc::Receiver { inner: receiver, meta },
+        )
+    }
+}
+
+/// Like [`crossbeam_channel::bounded`].
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    builder().bounded(cap)
+}
+
+/// Like [`crossbeam_channel::unbounded`].
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    builder().unbounded()
+}
+
+/// A traced [`crossbeam_channel::Sender`].
+pub struct Sender<T> {
+    inner: crossbeam_channel::Sender<Envelope<T>>,
+    meta: Meta,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            inner: self.inner.clone(),
+            meta: self.meta.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.meta
+            .send(value, |envelope| self.inner.send(envelope).map_err(|e| SendError(e.0.value)))
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.meta.send(value, |envelope| {
+            self.inner.try_send(envelope).map_err(|e| match e {
+                TrySendError::Full(e) => TrySendError::Full(e.value),
+                TrySendError::Disconnected(e) => TrySendError::Disconnected(e.value),
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+/// A traced [`crossbeam_channel::Receiver`].
+pub struct Receiver<T> {
+    inner: crossbeam_channel::Receiver<Envelope<T>>,
+    meta: Meta,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver {
+            inner: self.inner.clone(),
+            meta: self.meta.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.meta.recv(|| self.inner.recv())
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.meta.recv(|| self.inner.try_recv())
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.meta.recv(|| self.inner.recv_timeout(timeout))
+    }
+
+    /// Blocks for messages until all senders are gone.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+
+    /// Takes the messages already queued without blocking.
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+/// Traced versions of the [`std::sync::mpsc`] channels.
+pub mod mpsc {
+    use std::{sync::mpsc, time::Duration};
+
+    pub use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError};
+
+    use super::{Envelope, Meta};
+
+    /// Like [`std::sync::mpsc::channel`].
+    pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        super::builder().mpsc()
+    }
+
+    /// Like [`std::sync::mpsc::sync_channel`].
+    pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+        super::builder().sync_mpsc(bound)
+    }
+
+    pub struct Sender<T> {
+        pub(super) inner: mpsc::Sender<Envelope<T>>,
+        pub(super) meta: Meta,
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            Sender {
+                inner: self.inner.clone(),
+                meta: self.meta.clone(),
+            }
+        }
+    }
+
+    impl<T> Sender<T> {
+        pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+            self.meta
+                .send(value, |envelope| self.inner.send(envelope).map_err(|e| SendError(e.0.value)))
+        }
+    }
+
+    pub struct SyncSender<T> {
+        pub(super) inner: mpsc::SyncSender<Envelope<T>>,
+        pub(super) meta: Meta,
+    }
+
+    impl<T> Clone for SyncSender<T> {
+        fn clone(&self) -> Self {
+            SyncSender {
+                inner: self.inner.clone(),
+                meta: self.meta.clone(),
+            }
+        }
+    }
+
+    impl<T> SyncSender<T> {
+        pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+            self.meta
+                .send(value, |envelope| self.inner.send(envelope).map_err(|e| SendError(e.0.value)))
+        }
+
+        pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+            self.meta.send(value, |envelope| {
+                self.inner.try_send(envelope).map_err(|e| match e {
+                    TrySendError::Full(e) => TrySendError::Full(e.value),
+                    TrySendError::Disconnected(e) => TrySendError::Disconnected(e.value),
+                })
+            })
+        }
+    }
+
+    pub struct Receiver<T> {
+        pub(super) inner: mpsc::Receiver<Envelope<T>>,
+        pub(super) meta: Meta,
+    }
+
+    impl<T> Receiver<T> {
+        pub fn recv(&self) -> Result<T, RecvError> {
+            self.meta.recv(|| self.inner.recv())
+        }
+
+        pub fn try_recv(&self) -> Result<T, TryRecvError> {
+            self.meta.recv(|| self.inner.try_recv())
+        }
+
+        pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+            self.meta.recv(|| self.inner.recv_timeout(timeout))
+        }
+
+        /// Blocks for messages until all senders are gone.
+        pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+            std::iter::from_fn(move || self.recv().ok())
+        }
+
+        /// Takes the messages already queued without blocking.
+        pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+            std::iter::from_fn(move || self.try_recv().ok())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "off"))]
+    use crate::capture;
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn crossbeam() {
+        let trace = capture(|| {
+            let (sender, receiver) = builder().name("jobs").queue_depth(true).unbounded();
+            sender.send(1).unwrap();
+            sender.send(2).unwrap();
+            assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1, 2]);
+        });
+
+        assert_eq!(trace.spans_named("send").count(), 2);
+        assert_eq!(trace.flows.len(), 2);
+
+        let recv = trace.span("recv").unwrap();
+        assert_eq!(recv.cat, "jobs");
+        assert!(recv.arg("latency_us").unwrap().parse::<f64>().unwrap() >= 0.0);
+
+        let flow = &trace.flows[0];
+        let ends: Vec<&str> = flow.points.iter().map(|p| trace.spans[p.span.unwrap()].name.as_str()).collect();
+        assert_eq!(ends, ["send", "recv"]);
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn std() {
+        let trace = capture(|| {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            sender.try_send("a").unwrap();
+            assert!(matches!(sender.try_send("b"), Err(mpsc::TrySendError::Full("b"))));
+            assert_eq!(receiver.recv(), Ok("a"));
+        });
+
+        assert_eq!(trace.spans_named("send").count(), 1);
+        assert_eq!(trace.flows.len(), 1);
+    }
+
+    #[test]
+    fn untraced() {
+        let (sender, receiver) = unbounded();
+        sender.send(()).unwrap();
+        drop(sender);
+        assert_eq!(receiver.iter().count(), 1);
+    }
+}
@@ -0,0 +1,512 @@
+//! Runtime control of a long-running process's tracing over a Unix socket.
+//!
+//! With a control socket configured, events are kept in a bounded in-memory flight recorder
+//! instead of being written to a file, and captures are started and stopped from outside with
+//! `chrometrace ctl`. Each request is one line; the reply is one line starting with `ok` or
+//! `error`:
+//!
+//! - `start [--filter TEXT] [--duration SECS] [--output PATH]` writes events whose name or
+//!   category contains `TEXT` to `PATH` until `stop`, or for `SECS` seconds.
+//! - `stop` ends the capture.
+//! - `dump [PATH]` writes the events in the flight recorder.
+//! - `stats` reports how many events were seen, kept and dropped.
+//!
+//! Outputs default to timestamped files in the tracer's `output_dir`, the working directory of
+//! the process unless set. [`request`]
+//! resolves relative output paths against the working directory of the caller instead.
+//!
+//! The socket is only accessible to its owner. Each connection is answered on a thread of its
+//! own, and one that does not send its request within a few seconds is dropped.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tracing_chrometrace::EventType;
+
+use crate::{trigger::Trigger, SimpleEvent};
+
+struct Capture {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    filter: Option<String>,
+    until: Option<Instant>,
+    events: usize,
+}
+
+impl Capture {
+    fn create(path: PathBuf, filter: Option<String>, until: Option<Instant>, metadata: &[SimpleEvent]) -> io::Result<Capture> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        writer.write_all(b"[\n")?;
+        let mut capture = Capture {
+            writer,
+            path,
+            filter,
+            until,
+            events: 0,
+        };
+        for event in metadata {
+            capture.write(event.clone())?;
+        }
+        Ok(capture)
+    }
+
+    fn matches(&self, event: &SimpleEvent) -> bool {
+        match &self.filter {
+            Some(filter) => event.ph == EventType::Metadata || event.name.contains(filter.as_str()) || event.cat.contains(filter.as_str()),
+            None => true,
+        }
+    }
+
+    fn write(&mut self, event: SimpleEvent) -> io::Result<()> {
+        if self.events > 0 {
+            self.writer.write_all(b",\n")?;
+        }
+        event.write_json(&mut self.writer)?;
+        self.events += 1;
+        Ok(())
+    }
+
+    /// Closes the trace, returning its path and number of events.
+    fn finish(mut self) -> io::Result<(PathBuf, usize)> {
+        self.writer.write_all(b"\n]\n")?;
+        self.writer.flush()?;
+        Ok((self.path, self.events))
+    }
+}
+
+/// The flight recorder and the capture in progress, shared by the writer and control threads.
+pub(crate) struct Recorder {
+    /// Metadata, such as thread names and the clock anchor, kept for every output.
+    metadata: Vec<SimpleEvent>,
+    ring: VecDeque<SimpleEvent>,
+    capacity: usize,
+    capture: Option<Capture>,
+    /// Directory outputs not given a path are written to.
+    dir: PathBuf,
+    triggers: Vec<Trigger>,
+    /// Minimum time between the starts of two triggered captures.
+    interval: Duration,
+    /// Most triggered captures to write, and how many were.
+    limit: usize,
+    fired: usize,
+    last_triggered: Option<Instant>,
+    triggered: Option<Capture>,
+    seen: u64,
+    dropped: u64,
+}
+
+impl Recorder {
+    pub(crate) fn new(capacity: usize) -> Recorder {
+        Recorder {
+            metadata: Vec::new(),
+            ring: VecDeque::new(),
+            capacity,
+            capture: None,
+            dir: PathBuf::new(),
+            triggers: Vec::new(),
+            interval: Duration::ZERO,
+            limit: 0,
+            fired: 0,
+            last_triggered: None,
+            triggered: None,
+            seen: 0,
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn in_dir(mut self, dir: PathBuf) -> Recorder {
+        self.dir = dir;
+        self
+    }
+
+    pub(crate) fn with_triggers(mut self, triggers: Vec<Trigger>, interval: Duration, limit: usize) -> Recorder {
+        self.triggers = triggers;
+        self.interval = interval;
+        self.limit = limit;
+        self
+    }
+
+    /// `prefix-<seconds since the epoch>.json` in the output directory.
+    fn timestamped(&self, prefix: &str) -> PathBuf {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        self.dir.join(format!("{}-{}.{:03}.json", prefix, now.as_secs(), now.subsec_millis()))
+    }
+
+    pub(crate) fn record(&mut self, event: SimpleEvent) {
+        self.seen += 1;
+        self.expire();
+
+        if let Some(capture) = self.capture.as_mut().filter(|c| c.matches(&event)) {
+            if let Err(e) = capture.write(event.clone()) {
+                eprintln!("chrometracer: capture to {} failed: {}", capture.path.display(), e);
+                self.capture = None;
+            }
+        }
+
+        if let Some(triggered) = self.triggered.as_mut() {
+            if let Err(e) = triggered.write(event.clone()) {
+                eprintln!("chrometracer: capture to {} failed: {}", triggered.path.display(), e);
+                self.triggered = None;
+            }
+        } else if self.fired < self.limit && self.last_triggered.is_none_or(|last| last.elapsed() >= self.interval) {
+            let fired = self.triggers.iter().find_map(|trigger| Some((trigger.fired_by(&event)?, trigger.before, trigger.after)));
+            if let Some((reason, before, after)) = fired {
+                self.last_triggered = Some(Instant::now());
+                self.fired += 1;
+                if let Err(e) = self.fire(&event, reason, before, after) {
+                    eprintln!("chrometracer: cannot write a triggered capture: {}", e);
+                }
+                if self.fired == self.limit {
+                    eprintln!("chrometracer: wrote {} triggered captures; triggers are now off", self.fired);
+                }
+            }
+        }
+
+        if event.ph == EventType::Metadata {
+            self.metadata.push(event);
+            return;
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.ring.len() == self.capacity {
+            self.ring.pop_front();
+            self.dropped += 1;
+        }
+        self.ring.push_back(event);
+    }
+
+    /// Starts a triggered capture with the history of `before` up to `event`, and `event`.
+    fn fire(&mut self, event: &SimpleEvent, reason: String, before: Duration, after: Duration) -> io::Result<()> {
+        let mut triggered = Capture::create(self.timestamped("trigger"), None, Some(Instant::now() + after), &self.metadata)?;
+        eprintln!("chrometracer: {}; writing {}", reason, triggered.path.display());
+        let since = event.to.saturating_sub(before);
+        for earlier in self.ring.iter().filter(|earlier| earlier.to >= since) {
+            triggered.write(earlier.clone())?;
+        }
+        triggered.write(event.clone())?;
+        triggered.write(SimpleEvent {
+            name: "trigger",
+            cat: "trigger",
+            ph: EventType::Instant,
+            from: event.to,
+            to: event.to,
+            id: 0,
+            tid: event.tid,
+            args: vec![("reason", reason)],
+        })?;
+        self.triggered = Some(triggered);
+        Ok(())
+    }
+
+    /// Ends the captures whose duration is over.
+    pub(crate) fn expire(&mut self) {
+        let over = |capture: &Option<Capture>| capture.as_ref().and_then(|c| c.until).is_some_and(|until| Instant::now() >= until);
+        if over(&self.capture) {
+            let _ = self.stop();
+        }
+        if over(&self.triggered) {
+            let _ = self.stop_triggered();
+        }
+    }
+
+    /// Ends the triggered capture, if one is being written.
+    pub(crate) fn stop_triggered(&mut self) -> io::Result<(PathBuf, usize)> {
+        match self.triggered.take() {
+            Some(triggered) => triggered.finish(),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no triggered capture")),
+        }
+    }
+
+    fn start(&mut self, path: PathBuf, filter: Option<String>, duration: Option<Duration>) -> io::Result<()> {
+        if let Some(capture) = &self.capture {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("already capturing to {}", capture.path.display())));
+        }
+        let until = duration.map(|duration| Instant::now() + duration);
+        self.capture = Some(Capture::create(path, filter, until, &self.metadata)?);
+        Ok(())
+    }
+
+    pub(crate) fn stop(&mut self) -> io::Result<(PathBuf, usize)> {
+        match self.capture.take() {
+            Some(capture) => capture.finish(),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "not capturing")),
+        }
+    }
+
+    /// Writes the contents of the flight recorder to `path`.
+    pub(crate) fn dump(&self, path: PathBuf) -> io::Result<(PathBuf, usize)> {
+        let mut dump = Capture::create(path, None, None, &self.metadata)?;
+        for event in &self.ring {
+            dump.write(event.clone())?;
+        }
+        dump.finish()
+    }
+
+    fn stats(&self) -> String {
+        let capturing = match &self.capture {
+            Some(capture) => capture.path.display().to_string(),
+            None => "none".to_string(),
+        };
+        format!(
+            "events={} kept={} capacity={} dropped={} capturing={} triggered={}",
+            self.seen,
+            self.ring.len(),
+            self.capacity,
+            self.dropped,
+            capturing,
+            self.triggered.as_ref().map_or("none".to_string(), |t| t.path.display().to_string())
+        )
+    }
+}
+
+/// Runs one request and returns the reply.
+fn execute(recorder: &Mutex<Recorder>, request: &str) -> String {
+    let mut words = request.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let words: Vec<&str> = words.collect();
+    let mut recorder = recorder.lock().unwrap_or_else(|e| e.into_inner());
+    recorder.expire();
+
+    let result = match command {
+        "start" => parse_start(&words).and_then(|(path, filter, duration)| {
+            let path = path.unwrap_or_else(|| recorder.timestamped("capture"));
+            recorder.start(path.clone(), filter, duration)?;
+            Ok(format!("capturing to {}", path.display()))
+        }),
+        "stop" => recorder.stop().map(|(path, events)| format!("wrote {} events to {}", events, path.display())),
+        "dump" => {
+            let path = words.first().map_or_else(|| recorder.timestamped("flight"), PathBuf::from);
+            recorder.dump(path).map(|(path, events)| format!("wrote {} events to {}", events, path.display()))
+        }
+        "stats" => Ok(recorder.stats()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown command \"{}\"", command))),
+    };
+
+    match result {
+        Ok(reply) => format!("ok {}", reply),
+        Err(e) => format!("error {}", e),
+    }
+}
+
+fn parse_start(words: &[&str]) -> io::Result<(Option<PathBuf>, Option<String>, Option<Duration>)> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let (mut path, mut filter, mut duration) = (None, None, None);
+    let mut words = words.iter();
+    while let Some(&flag) = words.next() {
+        let value = *words.next().ok_or_else(|| invalid(format!("{} needs a value", flag)))?;
+        match flag {
+            "--output" => path = Some(PathBuf::from(value)),
+            "--filter" => filter = Some(value.to_string()),
+            "--duration" => {
+                let seconds: f64 = value.parse().map_err(|_| invalid(format!("bad duration \"{}\"", value)))?;
+                duration = Some(Duration::try_from_secs_f64(seconds).map_err(|e| invalid(e.to_string()))?);
+            }
+            _ => return Err(invalid(format!("unknown option \"{}\"", flag))),
+        }
+    }
+    Ok((path, filter, duration))
+}
+
+/// How long a connection may take to send its request or read the reply.
+#[cfg(unix)]
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Makes the output paths of `request` absolute, relative to the working directory.
+#[cfg(unix)]
+fn absolute(request: &str) -> io::Result<String> {
+    let words: Vec<&str> = request.split_whitespace().collect();
+    let mut resolved = Vec::with_capacity(words.len());
+    for (i, word) in words.iter().enumerate() {
+        let output = match (words[0], i) {
+            ("dump", 1) => true,
+            ("start", _) => i > 0 && words[i - 1] == "--output",
+            _ => false,
+        };
+        if output {
+            resolved.push(std::path::absolute(word)?.display().to_string());
+        } else {
+            resolved.push(word.to_string());
+        }
+    }
+    Ok(resolved.join(" "))
+}
+
+/// Sends `request` to the tracer listening on `socket` and returns its reply.
+///
+/// Output paths in the request are resolved against the working directory of the caller.
+#[cfg(unix)]
+pub fn request(socket: impl AsRef<Path>, request: &str) -> io::Result<String> {
+    let request = absolute(request)?;
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    writeln!(stream, "{}", request)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+/// Binds a socket at `path` that only its owner can connect to.
+///
+/// The socket is bound in a new directory only the owner can enter, restricted to the owner
+/// there and only then moved to `path`, so that nobody else can connect in between. Setting the
+/// umask instead would affect files created by other threads meanwhile.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket file name"))?;
+    let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    // Fails if the directory exists, so one made by someone else is never used.
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("socket");
+    let bound = std::os::unix::net::UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, PermissionsExt::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&dir);
+    bound
+}
+
+/// Answers requests on the control socket at `path` until the process exits.
+#[cfg(unix)]
+pub(crate) fn listen(path: &Path, recorder: Arc<Mutex<Recorder>>) -> io::Result<()> {
+    // A socket left behind by an earlier run of the process.
+    if std::fs::metadata(path).is_ok() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let recorder = recorder.clone();
+            std::thread::spawn(move || {
+                let _ = stream.set_read_timeout(Some(TIMEOUT));
+                let _ = stream.set_write_timeout(Some(TIMEOUT));
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                if reader.read_line(&mut request).is_ok() {
+                    let reply = execute(&recorder, &request);
+                    let _ = writeln!(&stream, "{}", reply);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Trace;
+
+    fn span(name: &'static str) -> SimpleEvent {
+        timed(name, 0, 1)
+    }
+
+    fn timed(name: &'static str, from_us: u64, to_us: u64) -> SimpleEvent {
+        SimpleEvent::span(name, Duration::from_micros(from_us), Duration::from_micros(to_us), false, 1, Vec::new())
+    }
+
+    #[test]
+    fn requests() {
+        let dir = std::env::temp_dir().join(format!("chrometracer-control-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let recorder = Mutex::new(Recorder::new(2));
+        let record = |event| recorder.lock().unwrap().record(event);
+
+        record(span("early"));
+        let capture = dir.join("capture.json");
+        let reply = execute(&recorder, &format!("start --filter db --output {}", capture.display()));
+        assert!(reply.starts_with("ok capturing"), "{}", reply);
+        assert!(execute(&recorder, "start").starts_with("error"));
+
+        record(span("db.query"));
+        record(span("render"));
+        assert_eq!(execute(&recorder, "stats"), format!("ok events=3 kept=2 capacity=2 dropped=1 capturing={} triggered=none", capture.display()));
+        assert!(execute(&recorder, "stop").starts_with("ok wrote 1 events"));
+
+        let dump = dir.join("flight.json");
+        assert!(execute(&recorder, &format!("dump {}", dump.display())).starts_with("ok wrote 2 events"));
+        assert!(execute(&recorder, "restart").starts_with("error unknown command"));
+
+        let names = |path| Trace::open(path).unwrap().spans.into_iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names(&capture), ["db.query"]);
+        assert_eq!(names(&dump), ["db.query", "render"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_capture() {
+        let recorder = Mutex::new(Recorder::new(1));
+        assert!(execute(&recorder, "start --output /dev/full").starts_with("ok capturing"));
+        // Enough to fill the buffer, so the writes reach the full device.
+        for _ in 0..1_000 {
+            recorder.lock().unwrap().record(span("work"));
+        }
+        assert_eq!(execute(&recorder, "stop"), "error not capturing");
+
+        recorder.lock().unwrap().record(span("after"));
+        assert_eq!(recorder.lock().unwrap().ring[0].name, "after");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        let out = cwd.join("out.json").display().to_string();
+        assert_eq!(absolute("dump out.json").unwrap(), format!("dump {}", out));
+        assert_eq!(absolute("start --filter db --output out.json").unwrap(), format!("start --filter db --output {}", out));
+        assert_eq!(absolute("dump /tmp/flight.json").unwrap(), "dump /tmp/flight.json");
+        assert_eq!(absolute("stats").unwrap(), "stats");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket() {
+        use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+        let path = std::env::temp_dir().join(format!("chrometracer-socket-{}", std::process::id()));
+        listen(&path, Arc::new(Mutex::new(Recorder::new(1)))).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let staging = path.with_file_name(format!(".{}.{}", path.file_name().unwrap().to_string_lossy(), std::process::id()));
+        assert!(!staging.exists());
+
+        // A client that never sends its request does not hold up the others.
+        let _idle = UnixStream::connect(&path).unwrap();
+        assert!(request(&path, "stats").unwrap().starts_with("ok events=0"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn triggered() {
+        let slow = Trigger::span_longer("slow", Duration::from_millis(1)).before(Duration::from_millis(5)).after(Duration::ZERO);
+        let dir = std::env::temp_dir().join(format!("chrometracer-triggered-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut recorder = Recorder::new(10).in_dir(dir.clone()).with_triggers(vec![slow], Duration::ZERO, 1);
+
+        recorder.record(timed("old", 0, 1_000));
+        recorder.record(timed("recent", 8_000, 9_000));
+        recorder.record(timed("slow", 9_000, 12_000));
+        let path = recorder.triggered.as_ref().unwrap().path.clone();
+        assert_eq!(path.parent(), Some(dir.as_path()));
+        // Past the limit of one capture.
+        recorder.record(timed("slow", 12_000, 20_000));
+        assert!(recorder.triggered.is_none());
+
+        let trace = Trace::open(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<_> = trace.spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["recent", "slow"]);
+        assert_eq!(trace.instants[0].arg("reason"), Some("span slow took 3ms"));
+    }
+}
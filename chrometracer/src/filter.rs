@@ -0,0 +1,277 @@
+//! Writer-side filtering of short spans, keeping the call paths of slow ones.
+//!
+//! A span is kept when it lasts at least the threshold, when it is an ancestor of a kept span,
+//! or when it is a direct child of a span lasting at least the threshold, so a slow span shows
+//! what it spent its time in. Other spans are dropped. Spans finish before their parents, so
+//! short spans are held back until their parent is known to be kept or dropped; spans without
+//! a `span_id` are judged on their own duration. Events other than spans pass through.
+//!
+//! Only direct children of slow spans are kept, not every descendant: a short span under a
+//! short child of a slow span is dropped, so a slow span shows one level of detail.
+//!
+//! What is held back is bounded. Spans waiting for a parent, parents waiting to be kept as an
+//! ancestor, and slow spans whose late children are kept are all forgotten once they are older
+//! than [`HORIZON`] in trace time, or when there are more than [`MAX_WAITING`] of them. Short
+//! children of a span running longer than the horizon are therefore dropped.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    thread,
+    time::Duration,
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use tracing_chrometrace::EventType;
+
+use crate::{tracer::ChromeTracerMessage, SimpleEvent};
+
+/// How long a span waits for its parent, and a slow span for its late children.
+const HORIZON: Duration = Duration::from_secs(10);
+
+/// Most spans of each kind held back at once.
+const MAX_WAITING: usize = 100_000;
+
+fn id(event: &SimpleEvent, key: &str) -> Option<u64> {
+    event.args.iter().find(|(k, _)| *k == key)?.1.parse().ok()
+}
+
+/// Values by span id, forgotten in the order they were added once too old or too many.
+struct Waiting<V> {
+    entries: HashMap<u64, (Duration, V)>,
+    order: VecDeque<(Duration, u64)>,
+}
+
+impl<V> Waiting<V> {
+    fn new() -> Waiting<V> {
+        Waiting {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get_or_insert_with(&mut self, id: u64, at: Duration, f: impl FnOnce() -> V) -> &mut V {
+        let order = &mut self.order;
+        &mut self
+            .entries
+            .entry(id)
+            .or_insert_with(|| {
+                order.push_back((at, id));
+                (at, f())
+            })
+            .1
+    }
+
+    fn remove(&mut self, id: u64) -> Option<V> {
+        self.entries.remove(&id).map(|(_, value)| value)
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        self.entries.contains_key(&id)
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forgets entries added before `now - HORIZON`, and the oldest beyond `MAX_WAITING`.
+    /// Removed entries stay in `order` until they expire, so it is what is bounded.
+    fn expire(&mut self, now: Duration) {
+        while let Some(&(at, id)) = self.order.front() {
+            if at + HORIZON >= now && self.order.len() <= MAX_WAITING {
+                break;
+            }
+            self.order.pop_front();
+            // Entries removed since, or added again later, are skipped.
+            if self.entries.get(&id).is_some_and(|(added, _)| *added == at) {
+                self.entries.remove(&id);
+            }
+        }
+        if self.entries.is_empty() {
+            self.order.clear();
+        }
+    }
+}
+
+pub(crate) struct DurationFilter {
+    min: Duration,
+    /// Latest end of the events seen so far.
+    now: Duration,
+    /// Short spans waiting for their parent, by the id of the parent.
+    pending: Waiting<Vec<SimpleEvent>>,
+    /// Ancestors of kept spans, which have not finished yet.
+    needed: Waiting<()>,
+    /// Spans kept for their own duration, whose children finishing later are kept too.
+    slow: Waiting<()>,
+}
+
+impl DurationFilter {
+    pub(crate) fn new(min: Duration) -> DurationFilter {
+        DurationFilter {
+            min,
+            now: Duration::ZERO,
+            pending: Waiting::new(),
+            needed: Waiting::new(),
+            slow: Waiting::new(),
+        }
+    }
+
+    /// Filters `event`, returning the events now known to be kept.
+    pub(crate) fn filter(&mut self, event: SimpleEvent) -> Vec<SimpleEvent> {
+        if event.to > self.now {
+            self.now = event.to;
+            self.pending.expire(self.now);
+            self.needed.expire(self.now);
+            self.slow.expire(self.now);
+        }
+
+        if !matches!(event.ph, EventType::Complete | EventType::AsyncStart) {
+            return vec![event];
+        }
+        let long = event.to.saturating_sub(event.from) >= self.min;
+        let (Some(span), parent) = (id(&event, "span_id"), id(&event, "parent_id")) else {
+            return if long { vec![event] } else { Vec::new() };
+        };
+
+        let children = self.pending.remove(span).unwrap_or_default();
+        let needed = self.needed.remove(span).is_some();
+        let finished = parent.is_some_and(|parent| self.slow.contains(parent));
+        if !(long || needed || finished) {
+            if let Some(parent) = parent {
+                self.pending.get_or_insert_with(parent, event.to, Vec::new).push(event);
+            }
+            return Vec::new();
+        }
+
+        let at = event.to;
+        let mut kept = vec![event];
+        if long {
+            self.slow.get_or_insert_with(span, at, || ());
+            kept.extend(children);
+        }
+        if let Some(parent) = parent.filter(|_| !finished) {
+            self.needed.get_or_insert_with(parent, at, || ());
+        }
+        kept
+    }
+}
+
+/// Filters the events of `receiver` into `sender` until told to terminate.
+fn run(mut filter: DurationFilter, receiver: Receiver<ChromeTracerMessage>, sender: Sender<ChromeTracerMessage>) -> DurationFilter {
+    for message in receiver {
+        match message {
+            ChromeTracerMessage::ChromeEvent(event) => {
+                for kept in filter.filter(event) {
+                    let _ = sender.send(ChromeTracerMessage::ChromeEvent(kept));
+                }
+            }
+            ChromeTracerMessage::Terminate => {
+                let _ = sender.send(ChromeTracerMessage::Terminate);
+                break;
+            }
+        }
+    }
+    filter
+}
+
+/// Passes the events of `receiver` through a [`DurationFilter`] on a thread of its own.
+pub(crate) fn relay(min: Duration, receiver: Receiver<ChromeTracerMessage>) -> Receiver<ChromeTracerMessage> {
+    let (sender, filtered) = crossbeam_channel::unbounded();
+    thread::spawn(move || run(DurationFilter::new(min), receiver, sender));
+    filtered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "off"))]
+    use crate::capture;
+
+    fn span(name: &'static str, micros: u64, span: u64, parent: Option<u64>) -> SimpleEvent {
+        ended(name, 0, micros, span, parent)
+    }
+
+    fn ended(name: &'static str, from_us: u64, to_us: u64, span: u64, parent: Option<u64>) -> SimpleEvent {
+        let mut args = vec![("span_id", span.to_string())];
+        if let Some(parent) = parent {
+            args.push(("parent_id", parent.to_string()));
+        }
+        SimpleEvent::span(name, Duration::from_micros(from_us), Duration::from_micros(to_us), false, 1, args)
+    }
+
+    #[test]
+    fn slow_paths() {
+        let mut filter = DurationFilter::new(Duration::from_micros(50));
+        let mut kept = Vec::new();
+        for event in [
+            // A short span under a short parent, which is itself a direct child of a slow span.
+            span("noise", 1, 5, Some(4)),
+            span("short", 2, 4, Some(1)),
+            // A short child of a slow span, kept as its detail.
+            span("detail", 10, 6, Some(3)),
+            span("slow", 60, 3, Some(2)),
+            // A short ancestor of the slow span, running on another thread.
+            span("spawner", 5, 2, Some(1)),
+            span("main", 100, 1, None),
+        ] {
+            kept.extend(filter.filter(event).into_iter().map(|e| e.name));
+        }
+        assert_eq!(kept, ["slow", "detail", "spawner", "main", "short"]);
+        assert!(filter.pending.is_empty() && filter.needed.is_empty());
+    }
+
+    #[test]
+    fn bounded() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (filtered, output) = crossbeam_channel::unbounded();
+        let later = HORIZON.as_micros() as u64 * 2;
+        for event in [
+            // Children of a parent that never arrives.
+            ended("orphan", 0, 1, 2, Some(1)),
+            ended("orphan", 1, 2, 3, Some(1)),
+            // A slow span with a kept child whose own parent never arrives either.
+            ended("slow", 0, 100, 5, Some(4)),
+            // Much later, with every span above past the horizon.
+            ended("late", later, later + 1, 6, None),
+        ] {
+            sender.send(ChromeTracerMessage::ChromeEvent(event)).unwrap();
+        }
+        sender.send(ChromeTracerMessage::Terminate).unwrap();
+
+        let filter = run(DurationFilter::new(Duration::from_micros(50)), receiver, filtered);
+        assert!(filter.pending.is_empty() && filter.needed.is_empty() && filter.slow.is_empty());
+        assert!(filter.pending.order.is_empty() && filter.slow.order.is_empty());
+        let kept: Vec<_> = output
+            .try_iter()
+            .filter_map(|message| match message {
+                ChromeTracerMessage::ChromeEvent(event) => Some(event.name),
+                ChromeTracerMessage::Terminate => None,
+            })
+            .collect();
+        assert_eq!(kept, ["slow"]);
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[crate::instrument(min_duration = "1s")]
+    fn quick() {
+        inner();
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[crate::instrument]
+    fn inner() {}
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn min_duration() {
+        let mut outer = 0;
+        let trace = capture(|| {
+            let span = crate::enter();
+            outer = span.id();
+            quick();
+        });
+        assert!(trace.span("quick").is_none());
+        // Attributed to the enclosing span rather than to the one that was not recorded.
+        assert_eq!(trace.span("inner").unwrap().parent_id, Some(outer));
+    }
+}
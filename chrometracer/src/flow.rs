@@ -0,0 +1,99 @@
+//! Flow events, drawn by the viewer as arrows between the spans they are recorded in.
+//!
+//! Flows are recorded at [`Level::Info`], so a level filter below `info` drops them along with
+//! the spans they would connect.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing_chrometrace::EventType;
+
+use crate::{current, enabled, Level, SimpleEvent};
+
+fn flow(ph: EventType, id: u64) {
+    if cfg!(feature = "off") || !enabled(Level::Info) {
+        return;
+    }
+
+    current(|tracer| {
+        if let Some(tracer) = tracer {
+            let now = tracer.start.elapsed();
+            tracer.trace(SimpleEvent {
+                name: "flow",
+                cat: "flow",
+                ph,
+                from: now,
+                to: now,
+                id,
+                tid: tracer.tid,
+                args: Vec::new(),
+            });
+        }
+    })
+}
+
+/// Returns an id no other call returns, for flows that need one generated.
+pub fn flow_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Starts flow `id` in the span currently running on this thread.
+///
+/// The viewer draws an arrow from here to the matching [`flow_step`]s and [`flow_end`], which
+/// may happen on other threads. Ids only need to be unique among flows alive at the same time.
+#[inline]
+pub fn flow_start(id: u64) {
+    flow(EventType::FlowStart, id)
+}
+
+/// Continues flow `id` in the span currently running on this thread.
+#[inline]
+pub fn flow_step(id: u64) {
+    flow(EventType::FlowStep, id)
+}
+
+/// Ends flow `id` in the span currently running on this thread.
+///
+/// `#[instrument(follows_from = id)]` does this at the start of the instrumented function.
+#[inline]
+pub fn flow_end(id: u64) {
+    flow(EventType::FlowEnd, id)
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::capture;
+
+    #[crate::instrument]
+    fn produce(id: u64) {
+        flow_start(id);
+    }
+
+    #[crate::instrument]
+    fn forward(id: u64) {
+        flow_step(id);
+    }
+
+    #[crate::instrument(follows_from = id)]
+    fn consume(id: u64) {
+        let _ = id;
+    }
+
+    #[test]
+    fn linked() {
+        let trace = capture(|| {
+            produce(3);
+            forward(3);
+            consume(3);
+        });
+
+        assert_eq!(trace.flows.len(), 1);
+        let points: Vec<&str> = trace.flows[0]
+            .points
+            .iter()
+            .map(|p| trace.spans[p.span.unwrap()].name.as_str())
+            .collect();
+        assert_eq!(points, ["produce", "forward", "consume"]);
+    }
+}
@@ -0,0 +1,387 @@
+//! Tracing for futures, one slice per poll.
+//!
+//! A [`Traced`] future records every poll as a slice named after it on the thread that polled
+//! it, and its whole lifetime, from creation to completion, as an async span. A future dropped
+//! before completing ends its span when dropped, with `cancelled` set. Waking it starts a flow
+//! from wherever the waker was called, which the next poll ends, so the time between a wake-up
+//! and the poll it caused shows as an arrow.
+//!
+//! Polls should be short: a poll that takes long is usually a blocking call inside async code,
+//! stalling every other task of the worker thread. With a threshold set, through
+//! `long_poll` on the [`builder`](crate::builder) or [`Traced::with_long_poll`], each poll lasting at least that long is flagged with a
+//! `long_poll` instant event. The futures with long polls are kept in [`long_polls`] until the
+//! tracer's guard is dropped, which lists the worst offenders on stderr with
+//! `long_poll_summary` set.
+
+use std::{
+    backtrace::Backtrace,
+    collections::HashMap,
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    time::Duration,
+};
+
+use tracing_chrometrace::EventType;
+
+use crate::{current, enter, flow_id, tracer::{emit, start_if}, Level, SimpleEvent};
+
+pub trait FutureExt: Future + Sized {
+    /// Records the polls and lifetime of this future under `name`.
+    fn traced(self, name: &'static str) -> Traced<Self> {
+        Traced::new(self, name)
+    }
+}
+
+impl<F: Future> FutureExt for F {}
+
+fn flow(ph: EventType, at: Duration, id: u64) -> SimpleEvent {
+    SimpleEvent {
+        name: "wake",
+        cat: "task",
+        ph,
+        from: at,
+        to: at,
+        id,
+        tid: 0,
+        args: Vec::new(),
+    }
+}
+
+/// State shared between a future and its wakers.
+struct Shared {
+    /// Flow started by the latest wake-up and not yet ended by a poll, or 0.
+    pending: AtomicU64,
+}
+
+struct Wakeup {
+    inner: Waker,
+    shared: Arc<Shared>,
+}
+
+impl Wake for Wakeup {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(start) = start_if(Level::Info) {
+            let id = flow_id();
+            // Only the first wake-up before a poll gets an arrow.
+            if self.shared.pending.compare_exchange(0, id, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                emit(flow(EventType::FlowStart, start.elapsed(), id));
+            }
+        }
+        self.inner.wake_by_ref()
+    }
+}
+
+pub struct Traced<F: Future> {
+    inner: F,
+    name: &'static str,
+    id: u64,
+    /// When the future was created, until its span is recorded.
+    created: Option<Duration>,
+    polls: u64,
+    shared: Arc<Shared>,
+    long_poll: Option<Duration>,
+    /// The executor's waker and the one wrapping it, reused while the executor's stays the same.
+    waker: Option<(Waker, Waker)>,
+}
+
+impl<F: Future> Traced<F> {
+    pub fn new(inner: F, name: &'static str) -> Self {
+        Traced {
+            inner,
+            name,
+            id: flow_id(),
+            created: start_if(Level::Info).map(|start| start.elapsed()),
+            polls: 0,
+            shared: Arc::new(Shared {
+                pending: AtomicU64::new(0),
+            }),
+            long_poll: None,
+            waker: None,
+        }
+    }
+
+    /// Flags polls lasting at least `threshold`, whatever the threshold of the tracer.
+    pub fn with_long_poll(mut self, threshold: Duration) -> Self {
+        self.long_poll = Some(threshold);
+        self
+    }
+
+    /// Unique id of the future, recorded as the `task_id` arg of its slices.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Makes the first poll end flow `id`, as if woken by it.
+    pub fn follows_from(self, id: u64) -> Self {
+        self.shared.pending.store(id, Ordering::Relaxed);
+        self
+    }
+
+    /// Records the lifetime of the future as ending at `to`, unless it already has.
+    fn finish(&mut self, to: Duration, cancelled: bool) {
+        if let Some(created) = self.created.take() {
+            let mut args = vec![("polls", self.polls.to_string())];
+            if cancelled {
+                args.push(("cancelled", true.to_string()));
+            }
+            emit(SimpleEvent {
+                cat: "task",
+                id: self.id,
+                ..SimpleEvent::span(self.name, created, to, true, 0, args)
+            });
+        }
+    }
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: `inner` is only ever used pinned, below, and is not moved out of `self`, not
+        // even when dropped. The other fields are not pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let Some(start) = start_if(Level::Info) else {
+            return unsafe { Pin::new_unchecked(&mut this.inner) }.poll(cx);
+        };
+
+        let span = enter();
+        let from = start.elapsed();
+        let woken_by = this.shared.pending.swap(0, Ordering::AcqRel);
+        if woken_by != 0 {
+            emit(flow(EventType::FlowEnd, from, woken_by));
+        }
+
+        let waker = match &mut this.waker {
+            Some((inner, waker)) if inner.will_wake(cx.waker()) => waker,
+            cached => {
+                let waker = Waker::from(Arc::new(Wakeup {
+                    inner: cx.waker().clone(),
+                    shared: this.shared.clone(),
+                }));
+                &cached.insert((cx.waker().clone(), waker)).1
+            }
+        };
+        let result = unsafe { Pin::new_unchecked(&mut this.inner) }.poll(&mut Context::from_waker(waker));
+        let to = start.elapsed();
+        this.polls += 1;
+
+        let args = vec![("task_id", this.id.to_string()), ("poll", this.polls.to_string())];
+        emit(SimpleEvent {
+            cat: "poll",
+            ..SimpleEvent::span(this.name, from, to, false, 0, args).with_span(&span)
+        });
+
+        let (threshold, backtrace) = current(|tracer| {
+            let tracer = tracer.map(|t| (t.long_poll, t.long_poll_backtrace));
+            let (threshold, backtrace) = tracer.unwrap_or_default();
+            (this.long_poll.or(threshold), backtrace)
+        });
+        if threshold.is_some_and(|threshold| to - from >= threshold) {
+            long_poll(this.name, this.id, this.polls, from..to, backtrace);
+        }
+
+        if result.is_ready() {
+            this.finish(to, false);
+        }
+        result
+    }
+}
+
+impl<F: Future> Drop for Traced<F> {
+    fn drop(&mut self) {
+        if self.created.is_some() {
+            if let Some(start) = start_if(Level::Info) {
+                self.finish(start.elapsed(), true);
+            }
+        }
+    }
+}
+
+/// Polls of one future name that exceeded the long-poll threshold.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LongPolls {
+    pub name: &'static str,
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+static LONG_POLLS: Mutex<Option<HashMap<&'static str, LongPolls>>> = Mutex::new(None);
+
+fn long_poll(name: &'static str, task: u64, poll: u64, range: std::ops::Range<Duration>, backtrace: bool) {
+    let duration = range.end - range.start;
+    let mut args = vec![
+        ("task_id", task.to_string()),
+        ("poll", poll.to_string()),
+        ("duration_us", (duration.as_nanos() as f64 / 1000.0).to_string()),
+    ];
+    if backtrace {
+        args.push(("backtrace", Backtrace::force_capture().to_string()));
+    }
+    emit(SimpleEvent {
+        name,
+        cat: "long_poll",
+        ph: EventType::Instant,
+        from: range.start,
+        to: range.start,
+        id: 0,
+        tid: 0,
+        args,
+    });
+
+    let mut polls = LONG_POLLS.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = polls.get_or_insert_with(HashMap::new).entry(name).or_insert(LongPolls {
+        name,
+        count: 0,
+        total: Duration::ZERO,
+        max: Duration::ZERO,
+    });
+    entry.count += 1;
+    entry.total += duration;
+    entry.max = entry.max.max(duration);
+}
+
+fn sorted(polls: impl Iterator<Item = LongPolls>) -> Vec<LongPolls> {
+    let mut polls: Vec<LongPolls> = polls.collect();
+    polls.sort_by(|a, b| b.max.cmp(&a.max).then_with(|| a.name.cmp(b.name)));
+    polls
+}
+
+/// Futures that had long polls since the tracer was initialized, longest poll first.
+pub fn long_polls() -> Vec<LongPolls> {
+    let polls = LONG_POLLS.lock().unwrap_or_else(|e| e.into_inner());
+    sorted(polls.iter().flat_map(|p| p.values().cloned()))
+}
+
+/// Like [`long_polls`], forgetting them.
+pub(crate) fn take_long_polls() -> Vec<LongPolls> {
+    let polls = LONG_POLLS.lock().unwrap_or_else(|e| e.into_inner()).take();
+    sorted(polls.into_iter().flat_map(HashMap::into_values))
+}
+
+/// Writes the ten worst offenders of `polls`, if any.
+pub(crate) fn write_long_polls<W: Write>(writer: &mut W, threshold: Duration, polls: &[LongPolls]) -> io::Result<()> {
+    if polls.is_empty() {
+        return Ok(());
+    }
+
+    let width = polls.iter().take(10).map(|p| p.name.len()).max().unwrap_or(0).max(6);
+    writeln!(writer, "chrometracer: {} future(s) polled for longer than {:?}", polls.len(), threshold)?;
+    writeln!(writer, "{:<width$} {:>8} {:>12} {:>12}", "future", "polls", "max", "total", width = width)?;
+    for p in polls.iter().take(10) {
+        writeln!(
+            writer,
+            "{:<width$} {:>8} {:>12} {:>12}",
+            p.name,
+            p.count,
+            format!("{:?}", p.max),
+            format!("{:?}", p.total),
+            width = width
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::capture;
+
+    /// Returns pending once, waking itself, then completes.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn polls() {
+        let trace = capture(|| block_on(YieldOnce(false).traced("job")));
+
+        let polls: Vec<_> = trace.spans.iter().filter(|s| s.name == "job" && s.cat == "poll").collect();
+        assert_eq!(polls.len(), 2);
+        assert_eq!(polls[1].arg("poll"), Some("2"));
+
+        let task = trace.spans.iter().find(|s| s.name == "job" && s.is_async).unwrap();
+        assert_eq!(task.arg("polls"), Some("2"));
+
+        assert_eq!(trace.flows.len(), 1);
+        let (flow, _) = trace.flow_points(polls[1]).next().unwrap();
+        assert_eq!(flow.points.len(), 2);
+    }
+
+    #[test]
+    fn cancelled() {
+        let trace = capture(|| {
+            let mut future = Box::pin(std::future::pending::<()>().traced("idle"));
+            struct Executor(AtomicU64);
+            impl Wake for Executor {
+                fn wake(self: Arc<Self>) {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            let executor = Waker::from(Arc::new(Executor(AtomicU64::new(0))));
+            let mut cx = Context::from_waker(&executor);
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+            let waker = future.waker.as_ref().unwrap().1.data();
+            assert!(future.as_mut().poll(&mut cx).is_pending());
+            // The same executor waker is wrapped once.
+            assert_eq!(future.waker.as_ref().unwrap().1.data(), waker);
+        });
+
+        let task = trace.spans.iter().find(|s| s.name == "idle" && s.is_async).unwrap();
+        assert_eq!(task.arg("polls"), Some("2"));
+        assert_eq!(task.arg("cancelled"), Some("true"));
+    }
+
+    #[test]
+    fn long_poll() {
+        let blocking = async { std::thread::sleep(Duration::from_millis(2)) };
+        let trace = capture(|| block_on(blocking.traced("blocking").with_long_poll(Duration::from_millis(1))));
+
+        let instant = &trace.instants[0];
+        assert_eq!((instant.name.as_str(), instant.cat.as_str()), ("blocking", "long_poll"));
+        assert!(instant.arg("duration_us").unwrap().parse::<f64>().unwrap() >= 2000.0);
+
+        let polls = long_polls();
+        let blocking = polls.iter().find(|p| p.name == "blocking").unwrap();
+        assert_eq!(blocking.count, 1);
+
+        let mut summary = Vec::new();
+        write_long_polls(&mut summary, Duration::from_millis(1), &polls).unwrap();
+        assert!(String::from_utf8(summary).unwrap().contains("blocking"));
+
+        // Forgotten once the guard takes them.
+        assert!(take_long_polls().iter().any(|p| p.name == "blocking"));
+        assert!(long_polls().iter().all(|p| p.name != "blocking"));
+    }
+}
@@ -0,0 +1,415 @@
+//! I/O handles that record their calls.
+//!
+//! A [`Traced`] handle records each call to `read`, `write`, `fill_buf`, `flush` and `seek` as
+//! a span named after the handle, categorized `io`, with the operation as `op` and the number
+//! of bytes moved as `bytes`. Calls moving few bytes are merged into one span per batch, with
+//! the number of calls as `calls`, so a loop of tiny reads does not flood the trace. The
+//! throughput of each span is recorded as the `bytes_per_sec` counter of the handle.
+//!
+//! A batch only merges calls following each other closely, with no other span starting on the
+//! thread in between, so it does not cover time spent elsewhere. It is written by the next call
+//! that does not join it, or when the handle is dropped. `fill_buf` returns the same buffer
+//! until it is consumed, so its bytes are those passed to `consume`, and repeated calls before
+//! a `consume` are recorded as one.
+//!
+//! With the `tokio` feature, `Traced` also wraps Tokio's `AsyncRead`, `AsyncWrite`,
+//! `AsyncBufRead` and `AsyncSeek`, recording the polls that complete.
+
+use std::{
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use tracing_chrometrace::EventType;
+
+use crate::{
+    stack,
+    tracer::{emit, start_if},
+    Level, SimpleEvent,
+};
+
+/// Calls moving fewer bytes than this are merged, unless set otherwise.
+const AGGREGATE_BELOW: usize = 4096;
+
+/// Calls further apart than this are not merged.
+const MAX_GAP: Duration = Duration::from_millis(1);
+
+pub struct Traced<T> {
+    inner: T,
+    recorder: Recorder,
+}
+
+impl<T> Traced<T> {
+    pub fn new(inner: T, name: &'static str) -> Self {
+        Traced {
+            inner,
+            recorder: Recorder {
+                name,
+                aggregate_below: AGGREGATE_BELOW,
+                batch: None,
+                filled: None,
+            },
+        }
+    }
+
+    /// Merges consecutive calls of the same operation moving fewer than `bytes` bytes each,
+    /// until they add up to `bytes`. 0 records every call on its own.
+    pub fn aggregate_below(mut self, bytes: usize) -> Self {
+        self.recorder.aggregate_below = bytes;
+        self
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the wrapped handle, recording calls still batched.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+/// Calls merged into one span.
+struct Batch {
+    op: &'static str,
+    from: Duration,
+    to: Duration,
+    bytes: usize,
+    calls: usize,
+    /// Spans started on the thread when the last call started, from [`stack::started`].
+    started: u64,
+}
+
+struct Recorder {
+    name: &'static str,
+    aggregate_below: usize,
+    batch: Option<Batch>,
+    /// A `fill_buf` whose buffer has not been consumed yet.
+    filled: Option<Batch>,
+}
+
+impl Recorder {
+    /// Runs `f`, recording it as `op` when it succeeds.
+    fn call<R>(&mut self, op: &'static str, f: impl FnOnce() -> io::Result<R>, bytes: impl FnOnce(&R) -> usize) -> io::Result<R> {
+        let Some(start) = start_if(Level::Info) else {
+            return f();
+        };
+
+        let started = stack::started();
+        let from = start.elapsed();
+        let result = f();
+        if let Ok(r) = &result {
+            self.record(op, from, start.elapsed(), bytes(r), started);
+        }
+        result
+    }
+
+    fn record(&mut self, op: &'static str, from: Duration, to: Duration, bytes: usize, started: u64) {
+        if op == "fill_buf" {
+            let fill = self.filled.get_or_insert(Batch {
+                op,
+                from,
+                to,
+                bytes: 0,
+                calls: 1,
+                started,
+            });
+            fill.to = to;
+            return;
+        }
+        self.merge(op, from, to, bytes, started);
+    }
+
+    /// Records the bytes of the last `fill_buf` once they are consumed.
+    fn consume(&mut self, amt: usize) {
+        if let Some(fill) = self.filled.take() {
+            self.merge(fill.op, fill.from, fill.to, amt, fill.started);
+        }
+    }
+
+    fn merge(&mut self, op: &'static str, from: Duration, to: Duration, bytes: usize, started: u64) {
+        let joins = self.batch.as_ref().is_some_and(|batch| {
+            batch.op == op && batch.started + 1 == started && from.saturating_sub(batch.to) <= MAX_GAP
+        });
+        if !joins {
+            self.flush();
+        }
+
+        if bytes >= self.aggregate_below {
+            self.emit(Batch {
+                op,
+                from,
+                to,
+                bytes,
+                calls: 1,
+                started,
+            });
+            return;
+        }
+
+        let batch = self.batch.get_or_insert(Batch {
+            op,
+            from,
+            to,
+            bytes: 0,
+            calls: 0,
+            started,
+        });
+        batch.to = to;
+        batch.started = started;
+        batch.bytes += bytes;
+        batch.calls += 1;
+        if batch.bytes >= self.aggregate_below {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            self.emit(batch);
+        }
+    }
+
+    fn emit(&self, batch: Batch) {
+        let mut args = vec![("op", batch.op.to_string()), ("bytes", batch.bytes.to_string())];
+        if batch.calls > 1 {
+            args.push(("calls", batch.calls.to_string()));
+        }
+        emit(SimpleEvent {
+            cat: "io",
+            ..SimpleEvent::span(self.name, batch.from, batch.to, false, 0, args)
+        });
+
+        if batch.bytes == 0 {
+            return;
+        }
+        let seconds = (batch.to - batch.from).as_secs_f64().max(1e-9);
+        let rate = |at, rate: f64| SimpleEvent {
+            name: self.name,
+            cat: "io",
+            ph: EventType::Counter,
+            from: at,
+            to: at,
+            id: 0,
+            tid: 0,
+            args: vec![("bytes_per_sec", rate.round().to_string())],
+        };
+        emit(rate(batch.from, batch.bytes as f64 / seconds));
+        emit(rate(batch.to, 0.0));
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.consume(0);
+        self.flush();
+    }
+}
+
+impl<R: Read> Read for Traced<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.recorder.call("read", || inner.read(buf), |&n| n)
+    }
+}
+
+impl<R: BufRead> BufRead for Traced<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let Traced { inner, recorder } = self;
+        recorder.call("fill_buf", || inner.fill_buf(), |_| 0)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt);
+        self.recorder.consume(amt);
+    }
+}
+
+impl<W: Write> Write for Traced<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.recorder.call("write", || inner.write(buf), |&n| n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.recorder.call("flush", || inner.flush(), |_| 0)
+    }
+}
+
+impl<S: Seek> Seek for Traced<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let inner = &mut self.inner;
+        self.recorder.call("seek", || inner.seek(pos), |_| 0)
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod r#async {
+    use std::{
+        io::{self, SeekFrom},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use ::tokio::io::{AsyncBufRead, AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+    use super::{Recorder, Traced};
+    use crate::{stack, tracer::start_if, Level};
+
+    impl Recorder {
+        /// Polls `f`, recording it as `op` when it completes successfully.
+        fn poll<R>(&mut self, op: &'static str, f: impl FnOnce() -> Poll<io::Result<R>>, bytes: impl FnOnce(&R) -> usize) -> Poll<io::Result<R>> {
+            let Some(start) = start_if(Level::Info) else {
+                return f();
+            };
+
+            let started = stack::started();
+            let from = start.elapsed();
+            let poll = f();
+            if let Poll::Ready(Ok(r)) = &poll {
+                self.record(op, from, start.elapsed(), bytes(r), started);
+            }
+            poll
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for Traced<R> {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let Traced { inner, recorder } = self.get_mut();
+            let read = || {
+                let filled = buf.filled().len();
+                Pin::new(inner).poll_read(cx, buf).map_ok(|()| buf.filled().len() - filled)
+            };
+            recorder.poll("read", read, |&n| n).map_ok(|_| ())
+        }
+    }
+
+    impl<R: AsyncBufRead + Unpin> AsyncBufRead for Traced<R> {
+        fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+            let Traced { inner, recorder } = self.get_mut();
+            recorder.poll("fill_buf", || Pin::new(inner).poll_fill_buf(cx), |_| 0)
+        }
+
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            let Traced { inner, recorder } = self.get_mut();
+            Pin::new(inner).consume(amt);
+            recorder.consume(amt);
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for Traced<W> {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let Traced { inner, recorder } = self.get_mut();
+            recorder.poll("write", || Pin::new(inner).poll_write(cx, buf), |&n| n)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let Traced { inner, recorder } = self.get_mut();
+            recorder.poll("flush", || Pin::new(inner).poll_flush(cx), |_| 0)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let Traced { inner, recorder } = self.get_mut();
+            recorder.poll("shutdown", || Pin::new(inner).poll_shutdown(cx), |_| 0)
+        }
+    }
+
+    impl<S: AsyncSeek + Unpin> AsyncSeek for Traced<S> {
+        fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            Pin::new(&mut self.get_mut().inner).start_seek(position)
+        }
+
+        fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            let Traced { inner, recorder } = self.get_mut();
+            recorder.poll("seek", || Pin::new(inner).poll_complete(cx), |_| 0)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::capture;
+
+    #[test]
+    fn calls() {
+        let trace = capture(|| {
+            let mut file = Traced::new(Cursor::new(vec![0u8; 8192]), "file").aggregate_below(100);
+            let mut buf = [0u8; 10];
+            for _ in 0..25 {
+                file.read_exact(&mut buf).unwrap();
+            }
+            file.seek(SeekFrom::Start(0)).unwrap();
+            assert_eq!(file.read(&mut [0u8; 8192]).unwrap(), 8192);
+        });
+
+        let spans: Vec<_> = trace.spans_named("file").collect();
+        let ops: Vec<_> = spans.iter().map(|s| (s.arg("op").unwrap(), s.arg("bytes").unwrap(), s.arg("calls"))).collect();
+        assert_eq!(
+            ops,
+            [
+                ("read", "100", Some("10")),
+                ("read", "100", Some("10")),
+                ("read", "50", Some("5")),
+                ("seek", "0", None),
+                ("read", "8192", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_end() {
+        let trace = capture(|| {
+            let mut file = Traced::new(Cursor::new(vec![0u8; 100]), "file");
+            let mut buf = [0u8; 10];
+            file.read_exact(&mut buf).unwrap();
+            file.read_exact(&mut buf).unwrap();
+            // Not merged across idle time...
+            std::thread::sleep(MAX_GAP * 5);
+            file.read_exact(&mut buf).unwrap();
+            // ...nor across another span on the thread.
+            drop(crate::enter());
+            file.read_exact(&mut buf).unwrap();
+        });
+
+        let calls: Vec<_> = trace.spans_named("file").map(|s| s.arg("calls")).collect();
+        assert_eq!(calls, [Some("2"), None, None]);
+    }
+
+    #[test]
+    fn consumed() {
+        let trace = capture(|| {
+            let mut lines = Traced::new(Cursor::new(b"ab\ncd\n".to_vec()), "lines").aggregate_below(0);
+            let mut line = String::new();
+            while lines.read_line(&mut line).unwrap() > 0 {}
+        });
+
+        let bytes: Vec<_> = trace.spans_named("lines").map(|s| s.arg("bytes").unwrap()).collect();
+        assert_eq!(bytes, ["3", "3", "0"]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_read() {
+        use std::{pin::Pin, task::{Context, Poll, Waker}};
+
+        use ::tokio::io::{AsyncRead, ReadBuf};
+
+        let trace = capture(|| {
+            let mut reader = Traced::new(&b"hello"[..], "bytes").aggregate_below(0);
+            let mut buf = [0u8; 16];
+            let mut buf = ReadBuf::new(&mut buf);
+            let poll = Pin::new(&mut reader).poll_read(&mut Context::from_waker(Waker::noop()), &mut buf);
+            assert!(matches!(poll, Poll::Ready(Ok(()))));
+        });
+
+        assert_eq!(trace.span("bytes").unwrap().arg("bytes"), Some("5"));
+    }
+}
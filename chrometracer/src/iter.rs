@@ -0,0 +1,146 @@
+//! Tracing for iterators, so stages of a streaming pipeline show up without moving them into
+//! `#[instrument]` functions.
+//!
+//! ```ignore
+//! use chrometracer::iter::IteratorExt;
+//!
+//! let rows = lines.map(parse).traced("parse").sample_items(100).filter(valid).count();
+//! ```
+//!
+//! A [`Traced`] iterator records one `iter` span from the first call to `next` until it is
+//! exhausted or dropped, with the number of items it produced as `items`. Optionally, calls to
+//! `next` are recorded too, as `item` spans nested in it with the index of the item as `index`.
+//! Whether an iterator is traced is decided on its first call to `next`, so one started while
+//! tracing was off stays untraced and costs nothing more per item.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    enter_detached,
+    stack::enter_child_of,
+    tracer::{emit, start_if},
+    Entered, Level, SimpleEvent,
+};
+
+pub trait IteratorExt: Iterator + Sized {
+    /// Records the iteration under `name`.
+    fn traced(self, name: &'static str) -> Traced<Self> {
+        Traced {
+            inner: self,
+            name,
+            every: None,
+            started: None,
+            finished: None,
+            index: 0,
+        }
+    }
+}
+
+impl<I: Iterator> IteratorExt for I {}
+
+pub struct Traced<I> {
+    inner: I,
+    name: &'static str,
+    /// Record every `every`-th call to `next`.
+    every: Option<usize>,
+    /// Whether the iteration is traced, decided on the first call to `next`: the start of the
+    /// tracer, the start of the span and the span if so.
+    started: Option<Option<(Instant, Duration, Entered)>>,
+    finished: Option<Duration>,
+    index: usize,
+}
+
+impl<I> Traced<I> {
+    /// Also records every call to `next`.
+    pub fn each_item(self) -> Self {
+        self.sample_items(1)
+    }
+
+    /// Also records every `n`-th call to `next`, starting with the first.
+    pub fn sample_items(mut self, n: usize) -> Self {
+        self.every = Some(n.max(1));
+        self
+    }
+}
+
+impl<I: Iterator> Iterator for Traced<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        let started = self
+            .started
+            .get_or_insert_with(|| start_if(Level::Info).map(|start| (start, start.elapsed(), enter_detached())));
+        let Some((start, _, span)) = started else {
+            return self.inner.next();
+        };
+
+        let index = self.index;
+        let sampled = self.every.is_some_and(|every| index.is_multiple_of(every));
+        let item = sampled.then(|| (start.elapsed(), enter_child_of(span.id())));
+
+        let next = self.inner.next();
+        let to = start.elapsed();
+        if next.is_some() {
+            self.index += 1;
+        } else {
+            self.finished.get_or_insert(to);
+        }
+
+        if let (Some((from, item)), true) = (item, next.is_some()) {
+            emit(SimpleEvent {
+                cat: "item",
+                ..SimpleEvent::span(self.name, from, to, false, 0, vec![("index", index.to_string())]).with_span(&item)
+            });
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<I> Drop for Traced<I> {
+    fn drop(&mut self) {
+        if let Some(Some((start, from, span))) = self.started.take() {
+            let to = self.finished.unwrap_or_else(|| start.elapsed());
+            emit(SimpleEvent {
+                cat: "iter",
+                ..SimpleEvent::span(self.name, from, to, false, 0, vec![("items", self.index.to_string())]).with_span(&span)
+            });
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::capture;
+
+    #[test]
+    fn stages() {
+        let trace = capture(|| {
+            let sum: u32 = (0..10).traced("numbers").sample_items(3).map(|x| x * 2).sum();
+            assert_eq!(sum, 90);
+        });
+
+        let stage = trace.spans.iter().find(|s| s.cat == "iter").unwrap();
+        assert_eq!(stage.arg("items"), Some("10"));
+
+        let items: Vec<_> = trace.children(stage).collect();
+        let indices: Vec<_> = items.iter().map(|s| s.arg("index").unwrap()).collect();
+        assert_eq!(indices, ["0", "3", "6", "9"]);
+        assert!(items.iter().all(|s| s.cat == "item"));
+    }
+
+    #[test]
+    fn decided_once() {
+        let mut numbers = (0..10).traced("numbers");
+        assert_eq!(numbers.next(), Some(0));
+
+        // Not traced from the first item on, so not from midway either.
+        let trace = capture(|| assert_eq!(numbers.by_ref().count(), 9));
+        drop(numbers);
+        assert!(trace.spans.is_empty());
+    }
+}
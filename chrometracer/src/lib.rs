@@ -1,9 +1,36 @@
 #![feature(thread_id_value)]
 
+extern crate self as chrometracer;
+
+mod assertions;
+pub mod channel;
+pub mod control;
+mod filter;
+mod flow;
+pub mod future;
+pub mod io;
+pub mod iter;
+mod level;
+pub mod process;
+#[cfg(feature = "rayon")]
+pub mod rayon;
+pub mod reader;
+mod stack;
+pub mod sync;
+pub mod thread;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+mod trace;
 mod tracer;
+pub mod trigger;
+pub mod wire;
 
 pub use chrometracer_attributes::instrument;
-pub use tracer::{builder, current, Recordable};
+pub use flow::{flow_end, flow_id, flow_start, flow_step};
+pub use level::{enabled, max_level, set_max_level, Level, LevelFilter, STATIC_MAX_LEVEL};
+pub use stack::{current_span, enter, enter_detached, Entered};
+pub use trace::{Flow, FlowPoint, Span, Thread, Trace};
+pub use tracer::{builder, capture, current, Recordable};
 pub use tracing_chrometrace::ChromeEvent;
 pub use tracing_chrometrace::EventType;
 
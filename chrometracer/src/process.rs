@@ -0,0 +1,215 @@
+//! Child processes recorded from spawn to exit.
+//!
+//! [`CommandExt`] runs a [`Command`] and records the lifetime of the child as an async `process`
+//! span with its command line as `argv`, its pid and its exit `status`. With
+//! [`CommandExt::trace_to`], a child using chrometracer writes its own trace to the given file,
+//! at the level of the parent, so it can be merged into the parent's timeline afterwards; the
+//! span then records the file as `trace`. Otherwise the child is started without
+//! `CHROMETRACER_FILE`, so a child that uses chrometracer does not overwrite the trace of its
+//! parent. Children started with [`Command`] directly inherit the variable as usual.
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::Path,
+    process::{self, Command, ExitStatus, Output},
+    time::Duration,
+};
+
+use crate::{
+    flow_id, max_level,
+    tracer::{emit, start_if},
+    Level, SimpleEvent,
+};
+
+/// Environment variable overriding the file a tracer writes to, `trace.json` by default.
+pub const FILE_VAR: &str = "CHROMETRACER_FILE";
+
+/// Environment variable naming the socket of a collector to stream events to.
+pub const SOCKET_VAR: &str = "CHROMETRACER_SOCKET";
+
+/// Environment variable naming the socket a tracer accepts [`crate::control`] requests on.
+pub const CONTROL_VAR: &str = "CHROMETRACER_CONTROL";
+
+/// Environment variable setting the level filter of a tracer not given one explicitly.
+pub const LEVEL_VAR: &str = "CHROMETRACER_LEVEL";
+
+pub trait CommandExt {
+    /// Has the child, if it uses chrometracer, write its trace to `path` at the current level.
+    fn trace_to(&mut self, path: impl AsRef<Path>) -> &mut Command;
+
+    /// Like [`Command::spawn`], recording the child once it is waited for.
+    fn spawn_traced(&mut self) -> io::Result<Child>;
+
+    /// Like [`Command::status`].
+    fn status_traced(&mut self) -> io::Result<ExitStatus> {
+        self.spawn_traced()?.wait()
+    }
+
+    /// Like [`Command::output`].
+    fn output_traced(&mut self) -> io::Result<Output>;
+}
+
+impl CommandExt for Command {
+    fn trace_to(&mut self, path: impl AsRef<Path>) -> &mut Command {
+        self.env(FILE_VAR, path.as_ref()).env(LEVEL_VAR, max_level().to_string())
+    }
+
+    fn spawn_traced(&mut self) -> io::Result<Child> {
+        if !self.get_envs().any(|(key, _)| key == FILE_VAR) {
+            self.env_remove(FILE_VAR);
+        }
+        let from = start_if(Level::Info).map(|start| start.elapsed());
+        let child = self.spawn()?;
+        let recording = from.map(|from| {
+            let mut args = vec![("argv", argv(self)), ("pid", child.id().to_string())];
+            let trace = self.get_envs().find(|(key, _)| *key == FILE_VAR).and_then(|(_, path)| path);
+            if let Some(trace) = trace {
+                args.push(("trace", trace.to_string_lossy().into_owned()));
+            }
+            Recording { from, args }
+        });
+        Ok(Child { inner: child, recording })
+    }
+
+    fn output_traced(&mut self) -> io::Result<Output> {
+        self.stdout(process::Stdio::piped()).stderr(process::Stdio::piped());
+        self.spawn_traced()?.wait_with_output()
+    }
+}
+
+/// The command line, with arguments quoted where needed.
+fn argv(command: &Command) -> String {
+    let quote = |arg: &OsStr| {
+        let arg = arg.to_string_lossy();
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+            format!("{:?}", arg)
+        } else {
+            arg.into_owned()
+        }
+    };
+
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug)]
+struct Recording {
+    from: Duration,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Recording {
+    fn finish(self, status: ExitStatus) {
+        let Some(start) = start_if(Level::Info) else {
+            return;
+        };
+
+        let Recording { from, mut args } = self;
+        args.push(("status", status.code().map_or_else(|| status.to_string(), |code| code.to_string())));
+        emit(SimpleEvent {
+            cat: "process",
+            id: flow_id(),
+            ..SimpleEvent::span("process", from, start.elapsed(), true, 0, args)
+        });
+    }
+}
+
+/// A running child, recorded when it is waited for.
+#[derive(Debug)]
+pub struct Child {
+    inner: process::Child,
+    recording: Option<Recording>,
+}
+
+impl Child {
+    pub fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    /// The wrapped child, to reach its stdio handles.
+    pub fn get_mut(&mut self) -> &mut process::Child {
+        &mut self.inner
+    }
+
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.inner.kill()
+    }
+
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.inner.wait()?;
+        if let Some(recording) = self.recording.take() {
+            recording.finish(status);
+        }
+        Ok(status)
+    }
+
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        let status = self.inner.try_wait()?;
+        if let (Some(status), Some(recording)) = (status, self.recording.take()) {
+            recording.finish(status);
+        }
+        Ok(status)
+    }
+
+    pub fn wait_with_output(self) -> io::Result<Output> {
+        let output = self.inner.wait_with_output()?;
+        if let Some(recording) = self.recording {
+            recording.finish(output.status);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::{capture, LevelFilter};
+
+    #[test]
+    fn lifetime() {
+        let trace = capture(|| {
+            let status = Command::new("sh").args(["-c", "exit 3"]).status_traced().unwrap();
+            assert_eq!(status.code(), Some(3));
+        });
+
+        let process = trace.span("process").unwrap();
+        assert!(process.is_async);
+        assert_eq!(process.arg("argv"), Some("sh -c \"exit 3\""));
+        assert_eq!(process.arg("status"), Some("3"));
+    }
+
+    #[test]
+    fn configured() {
+        let trace = capture(|| {
+            let output = Command::new("sh")
+                .args(["-c", "echo $CHROMETRACER_FILE $CHROMETRACER_LEVEL"])
+                .trace_to("child.json")
+                .output_traced()
+                .unwrap();
+            let output = String::from_utf8(output.stdout).unwrap();
+            let (file, level) = output.trim_end().split_once(' ').unwrap();
+            assert_eq!(file, "child.json");
+            assert_eq!(level.parse::<LevelFilter>(), Ok(max_level()));
+        });
+
+        assert_eq!(trace.span("process").unwrap().arg("trace"), Some("child.json"));
+    }
+
+    #[test]
+    fn own_file() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo ${CHROMETRACER_FILE-unset}"]);
+        let trace = capture(|| {
+            let output = command.output_traced().unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), "unset\n");
+        });
+
+        // Removed even when this process was given a file itself.
+        assert!(command.get_envs().any(|(key, value)| key == FILE_VAR && value.is_none()));
+        assert_eq!(trace.span("process").unwrap().arg("trace"), None);
+    }
+}
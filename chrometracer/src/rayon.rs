@@ -0,0 +1,340 @@
+//! Rayon integration: named pool workers and traced parallel iterators.
+//!
+//! ```ignore
+//! let pool = chrometracer::rayon::pool_builder().build()?;
+//! let total: u64 = pool.install(|| {
+//!     inputs.par_iter().traced("parse").map(parse).sum()
+//! });
+//! ```
+//!
+//! A [`traced`](ParallelIteratorExt::traced) iterator records a `par_iter` span on the calling
+//! thread for as long as the iteration runs, and each chunk of items a worker processes in one
+//! go as a `rayon` span on that worker, with the number of items as `items`. A flow links the
+//! call to every chunk, and the chunks record the calling span as their `parent_id`. The number
+//! of workers busy with traced chunks is recorded as the `active` counter of `rayon`. A chunk
+//! a panic unwinds through is still recorded, with `panicked` set.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ::rayon::{
+    iter::{
+        plumbing::{Consumer, Folder, UnindexedConsumer},
+        ParallelIterator,
+    },
+    ThreadPoolBuilder,
+};
+use tracing_chrometrace::EventType;
+
+use crate::{
+    current, enter, flow_id,
+    stack::enter_child_of,
+    thread::name_current_thread,
+    tracer::{emit, replace, start_if, ChromeTracer},
+    Entered, Level, SimpleEvent,
+};
+
+/// Names worker threads `rayon-worker-0`, `rayon-worker-1`, ..., for
+/// [`ThreadPoolBuilder::thread_name`].
+pub fn thread_name(index: usize) -> String {
+    format!("rayon-worker-{}", index)
+}
+
+/// Names the track of a pool worker after the thread, for [`ThreadPoolBuilder::start_handler`].
+pub fn start_handler(_index: usize) {
+    name_current_thread();
+}
+
+/// A pool builder with [`thread_name`] and [`start_handler`] set.
+pub fn pool_builder() -> ThreadPoolBuilder {
+    ThreadPoolBuilder::new().thread_name(thread_name).start_handler(start_handler)
+}
+
+pub trait ParallelIteratorExt: ParallelIterator {
+    /// Records the chunks of this iterator run by each worker under `name`.
+    ///
+    /// The result is no longer indexed, so it supports the methods of [`ParallelIterator`]
+    /// only.
+    fn traced(self, name: &'static str) -> Traced<Self> {
+        Traced { base: self, name }
+    }
+}
+
+impl<I: ParallelIterator> ParallelIteratorExt for I {}
+
+#[derive(Clone, Debug)]
+pub struct Traced<I> {
+    base: I,
+    name: &'static str,
+}
+
+/// The traced iteration, shared by all its chunks.
+struct Call {
+    tracer: ChromeTracer,
+    name: &'static str,
+    tid: u64,
+    from: Duration,
+    span: u64,
+}
+
+static ACTIVE: AtomicI64 = AtomicI64::new(0);
+
+thread_local! {
+    /// Start of the tracer the calling thread's track was last named for.
+    static NAMED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+impl<I: ParallelIterator> ParallelIterator for Traced<I> {
+    type Item = I::Item;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        let Some(start) = start_if(Level::Info) else {
+            return self.base.drive_unindexed(consumer);
+        };
+        let Some(tracer) = current(|tracer| tracer.cloned()) else {
+            return self.base.drive_unindexed(consumer);
+        };
+
+        let span = enter();
+        let from = start.elapsed();
+        let call = Arc::new(Call {
+            name: self.name,
+            tid: tracer.tid,
+            from,
+            span: span.id(),
+            tracer,
+        });
+        let result = self.base.drive_unindexed(TracedConsumer { base: consumer, call });
+        let to = start.elapsed();
+
+        emit(SimpleEvent {
+            cat: "par_iter",
+            ..SimpleEvent::span(self.name, from, to, false, 0, Vec::new()).with_span(&span)
+        });
+        result
+    }
+
+    fn opt_len(&self) -> Option<usize> {
+        self.base.opt_len()
+    }
+}
+
+struct TracedConsumer<C> {
+    base: C,
+    call: Arc<Call>,
+}
+
+impl<T, C: Consumer<T>> Consumer<T> for TracedConsumer<C> {
+    type Folder = TracedFolder<C::Folder>;
+    type Reducer = C::Reducer;
+    type Result = C::Result;
+
+    fn split_at(self, index: usize) -> (Self, Self, Self::Reducer) {
+        let (left, right, reducer) = self.base.split_at(index);
+        let left = TracedConsumer {
+            base: left,
+            call: self.call.clone(),
+        };
+        let right = TracedConsumer {
+            base: right,
+            call: self.call,
+        };
+        (left, right, reducer)
+    }
+
+    fn into_folder(self) -> Self::Folder {
+        TracedFolder::new(self.base.into_folder(), self.call)
+    }
+
+    fn full(&self) -> bool {
+        self.base.full()
+    }
+}
+
+impl<T, C: UnindexedConsumer<T>> UnindexedConsumer<T> for TracedConsumer<C> {
+    fn split_off_left(&self) -> Self {
+        TracedConsumer {
+            base: self.base.split_off_left(),
+            call: self.call.clone(),
+        }
+    }
+
+    fn to_reducer(&self) -> Self::Reducer {
+        self.base.to_reducer()
+    }
+}
+
+struct TracedFolder<F> {
+    base: F,
+    chunk: Chunk,
+}
+
+impl<F> TracedFolder<F> {
+    fn new(base: F, call: Arc<Call>) -> Self {
+        let previous = replace(Some(call.tracer.clone()));
+        if NAMED.with(|named| named.replace(Some(call.tracer.start))) != Some(call.tracer.start) {
+            name_current_thread();
+        }
+        active(&call.tracer, 1);
+
+        let chunk = Chunk {
+            span: enter_child_of(call.span),
+            from: call.tracer.start.elapsed(),
+            call,
+            items: 0,
+            previous,
+        };
+        TracedFolder { base, chunk }
+    }
+}
+
+/// One chunk, recording into the tracer of the call while it runs.
+///
+/// The chunk is recorded and the worker's tracer restored when it is dropped, after the folder
+/// completes or while a panic in the iteration unwinds it, with `panicked` set then.
+struct Chunk {
+    call: Arc<Call>,
+    span: Entered,
+    from: Duration,
+    items: usize,
+    previous: Option<ChromeTracer>,
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let call = &self.call;
+        let to = call.tracer.start.elapsed();
+
+        let flow = flow_id();
+        let point = |ph, tid, at| SimpleEvent {
+            name: "par_iter",
+            cat: "rayon",
+            ph,
+            from: at,
+            to: at,
+            id: flow,
+            tid,
+            args: Vec::new(),
+        };
+        call.tracer.trace(point(EventType::FlowStart, call.tid, call.from));
+        emit(point(EventType::FlowEnd, 0, self.from));
+
+        let mut args = vec![("items", self.items.to_string())];
+        if std::thread::panicking() {
+            args.push(("panicked", true.to_string()));
+        }
+        emit(SimpleEvent {
+            cat: "rayon",
+            ..SimpleEvent::span(call.name, self.from, to, false, 0, args).with_span(&self.span)
+        });
+        active(&call.tracer, -1);
+
+        replace(self.previous.take());
+    }
+}
+
+fn active(tracer: &ChromeTracer, delta: i64) {
+    let active = ACTIVE.fetch_add(delta, Ordering::Relaxed) + delta;
+    let at = tracer.start.elapsed();
+    emit(SimpleEvent {
+        name: "rayon",
+        cat: "rayon",
+        ph: EventType::Counter,
+        from: at,
+        to: at,
+        id: 0,
+        tid: 0,
+        args: vec![("active", active.to_string())],
+    });
+}
+
+impl<T, F: Folder<T>> Folder<T> for TracedFolder<F> {
+    type Result = F::Result;
+
+    fn consume(mut self, item: T) -> Self {
+        self.chunk.items += 1;
+        self.base = self.base.consume(item);
+        self
+    }
+
+    fn consume_iter<I>(mut self, iter: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let mut items = 0;
+        self.base = self.base.consume_iter(iter.into_iter().inspect(|_| items += 1));
+        self.chunk.items += items;
+        self
+    }
+
+    fn complete(self) -> F::Result {
+        let TracedFolder { base, chunk } = self;
+        let result = base.complete();
+        drop(chunk);
+        result
+    }
+
+    fn full(&self) -> bool {
+        self.base.full()
+    }
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use ::rayon::prelude::*;
+
+    use super::*;
+    use crate::capture;
+
+    #[test]
+    fn chunks() {
+        let pool = pool_builder().num_threads(2).build().unwrap();
+
+        let trace = pool.install(|| {
+            capture(|| {
+                let sum: u64 = (0..1000u64).into_par_iter().traced("square").map(|x| x * x).sum();
+                assert_eq!(sum, 332833500);
+            })
+        });
+
+        let call = trace.spans.iter().find(|s| s.cat == "par_iter").unwrap();
+        let chunks: Vec<_> = trace.spans.iter().filter(|s| s.name == "square" && s.cat == "rayon").collect();
+        assert!(!chunks.is_empty());
+        assert_eq!(chunks.iter().map(|c| c.arg("items").unwrap().parse::<usize>().unwrap()).sum::<usize>(), 1000);
+        assert!(chunks.iter().all(|c| trace.caller(c).unwrap().cat == "par_iter"));
+
+        assert_eq!(trace.flows.len(), chunks.len());
+        let (flow, _) = trace.flow_points(call).next().unwrap();
+        assert_eq!(trace.spans[flow.points[1].span.unwrap()].cat, "rayon");
+    }
+
+    #[test]
+    fn panicking() {
+        let pool = pool_builder().num_threads(2).build().unwrap();
+
+        let trace = pool.install(|| {
+            capture(|| {
+                let result = std::panic::catch_unwind(|| {
+                    (0..1000u64).into_par_iter().traced("check").for_each(|x| assert_ne!(x, 500));
+                });
+                assert!(result.is_err());
+            })
+        });
+
+        let chunks: Vec<_> = trace.spans_named("check").filter(|s| s.cat == "rayon").collect();
+        assert!(chunks.iter().any(|c| c.arg("panicked") == Some("true")));
+        assert_eq!(trace.flows.len(), chunks.len());
+        // The workers no longer record into the captured trace, nor nest spans in the chunks.
+        let restored = pool.broadcast(|_| current(|tracer| tracer.is_none()) && crate::current_span().is_none());
+        assert!(restored.into_iter().all(|restored| restored));
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    time::SystemTime,
+};
+
+use serde_json::Value;
+use tracing_chrometrace::{ChromeEvent, EventType};
+
+use crate::trace::Trace;
+
+/// Streams events out of a Chrome trace.
+///
+/// Both the JSON array form and the `{"traceEvents": [...]}` object form are accepted. A
+/// trace cut short by a crash, with the closing `]` or the last event missing, ends at the
+/// last complete event instead of failing.
+pub struct Reader<R> {
+    reader: R,
+    state: State,
+    buf: Vec<u8>,
+}
+
+#[derive(PartialEq)]
+enum State {
+    Start,
+    Events,
+    Done,
+}
+
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Reader<BufReader<File>>> {
+    Ok(Reader::new(BufReader::new(File::open(path)?)))
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Reader {
+            reader,
+            state: State::Start,
+            buf: Vec::new(),
+        }
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.peek()?;
+        if byte.is_some() {
+            self.reader.consume(1);
+        }
+        Ok(byte)
+    }
+
+    /// Skips whitespace and commas and returns the next significant byte without consuming it.
+    fn skip_separators(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            match self.peek()? {
+                Some(b) if b.is_ascii_whitespace() || b == b',' => self.reader.consume(1),
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// Reads one JSON value into `buf`. Returns false if the input ends before the value does.
+    fn read_value(&mut self) -> io::Result<bool> {
+        self.buf.clear();
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        loop {
+            if depth == 0 && !in_string && !self.buf.is_empty() {
+                match self.peek()? {
+                    Some(b) if b.is_ascii_whitespace() || b",:]}".contains(&b) => return Ok(true),
+                    None => return Ok(!matches!(self.buf[0], b'{' | b'[' | b'"')),
+                    _ => {}
+                }
+            }
+
+            let byte = match self.next_byte()? {
+                Some(byte) => byte,
+                None => return Ok(false),
+            };
+            self.buf.push(byte);
+
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => {
+                        in_string = false;
+                        if depth == 0 {
+                            return Ok(true);
+                        }
+                    }
+                    _ => {}
+                }
+            } else {
+                match byte {
+                    b'"' => in_string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth = depth.saturating_sub(1);
+                        if depth == 0 {
+                            return Ok(true);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Positions the reader on the first event of the `traceEvents` array.
+    fn find_events(&mut self) -> io::Result<bool> {
+        match self.skip_separators()? {
+            Some(b'[') => {
+                self.reader.consume(1);
+                return Ok(true);
+            }
+            Some(b'{') => self.reader.consume(1),
+            Some(_) => return Err(invalid("expected a JSON array or object")),
+            None => return Ok(false),
+        }
+
+        loop {
+            if self.skip_separators()? != Some(b'"') || !self.read_value()? {
+                return Ok(false);
+            }
+            let key: String = serde_json::from_slice(&self.buf)?;
+
+            match self.skip_separators()? {
+                Some(b':') => self.reader.consume(1),
+                _ => return Err(invalid("expected ':' after an object key")),
+            }
+
+            if key == "traceEvents" {
+                return match self.skip_separators()? {
+                    Some(b'[') => {
+                        self.reader.consume(1);
+                        Ok(true)
+                    }
+                    _ => Err(invalid("\"traceEvents\" is not an array")),
+                };
+            }
+
+            self.skip_separators()?;
+            if !self.read_value()? {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<ChromeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result: io::Result<Option<ChromeEvent>> = (|| {
+            if self.state == State::Start {
+                self.state = if self.find_events()? { State::Events } else { State::Done };
+            }
+            if self.state == State::Done {
+                return Ok(None);
+            }
+
+            match self.skip_separators()? {
+                Some(b'{') if self.read_value()? => {
+                    let value: Value = serde_json::from_slice(&self.buf)?;
+                    event_from_value(value).map(Some)
+                }
+                Some(b'{') | Some(b']') | None => {
+                    self.state = State::Done;
+                    Ok(None)
+                }
+                Some(_) => Err(invalid("expected an event object")),
+            }
+        })();
+
+        if result.is_err() {
+            self.state = State::Done;
+        }
+        result.transpose()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn as_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn as_u64(value: Option<&Value>) -> u64 {
+    match value {
+        Some(Value::Number(n)) => n.as_u64().unwrap_or_default(),
+        Some(Value::String(s)) => s.parse().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Arg holding the `bp` field of flow events, which [`ChromeEvent`] has no field for.
+pub const BP_ARG: &str = "bp";
+
+/// Converts a JSON event, keeping its `bp` field as the [`BP_ARG`] arg.
+pub fn event_from_value(value: Value) -> io::Result<ChromeEvent> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| invalid("an event must be a JSON object"))?;
+
+    let ph: EventType = match object.get("ph") {
+        Some(ph) => serde_json::from_value(ph.clone())?,
+        None => return Err(invalid("an event must have a \"ph\" field")),
+    };
+
+    let mut builder = ChromeEvent::builder(SystemTime::UNIX_EPOCH);
+    builder
+        .name(object.get("name").map(as_string).unwrap_or_default())
+        .cat(object.get("cat").map(as_string).unwrap_or_default())
+        .ph(ph)
+        .ts(object.get("ts").and_then(Value::as_f64).unwrap_or_default())
+        .dur(object.get("dur").and_then(Value::as_f64))
+        .tts(object.get("tts").and_then(Value::as_f64))
+        .id(object.get("id").map(as_string).unwrap_or_default())
+        .pid(as_u64(object.get("pid")))
+        .tid(as_u64(object.get("tid")));
+
+    if let Some(Value::Object(args)) = object.get("args") {
+        for (key, value) in args {
+            builder.arg((key.clone(), as_string(value)));
+        }
+    }
+    if let Some(bp) = object.get("bp") {
+        builder.arg((BP_ARG.to_string(), as_string(bp)));
+    }
+
+    builder.build().map_err(|e| invalid(&e.to_string()))
+}
+
+/// Converts `event` to JSON, moving the [`BP_ARG`] arg back to the `bp` field.
+pub fn event_to_value(event: &ChromeEvent) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(event)?;
+    if let Some(Value::Object(args)) = value.get_mut("args") {
+        if let Some(bp) = args.remove(BP_ARG) {
+            if args.is_empty() {
+                value.as_object_mut().unwrap().remove("args");
+            }
+            value["bp"] = bp;
+        }
+    }
+    Ok(value)
+}
+
+impl Trace {
+    /// Reads a trace written by chrometracer or any other Chrome trace producer.
+    pub fn read<R: io::Read>(reader: R) -> io::Result<Trace> {
+        let events = Reader::new(BufReader::new(reader)).collect::<io::Result<Vec<_>>>()?;
+        Ok(Trace::from_events(events))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        Trace::read(File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reader;
+    use crate::{EventType, SimpleEvent, Trace};
+    use std::time::Duration;
+
+    fn read(input: &str) -> Vec<crate::ChromeEvent> {
+        Reader::new(input.as_bytes()).collect::<std::io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn array() {
+        let events = read(r#"[{"name":"a","ph":"X","ts":1.5,"dur":2,"pid":1,"tid":2,"args":{"n":3,"s":"x"}},
+            {"name":"b","ph":"i","ts":4,"pid":1,"tid":2}]"#);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "a");
+        assert_eq!(events[0].dur, Some(2.0));
+        assert_eq!(events[0].args["n"], "3");
+        assert_eq!(events[1].ph, EventType::Instant);
+    }
+
+    #[test]
+    fn object() {
+        let events = read(r#"{"displayTimeUnit":"ns","otherData":{"a":[1,"]"]},"traceEvents":[
+            {"name":"a","ph":"B","ts":1,"pid":1,"tid":1},{"name":"a","ph":"E","ts":2,"pid":1,"tid":1}],"meta":1}"#);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].ph, EventType::DurationEnd);
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(read("[\n{\"name\":\"a\",\"ph\":\"X\",\"ts\":1,\"dur\":1,\"pid\":1,\"tid\":1},\n").len(), 1);
+        assert_eq!(read("[{\"name\":\"a\",\"ph\":\"X\",\"ts\":1,\"dur\":1,\"pid\":1,\"tid\":1},{\"name\":\"b\",\"p").len(), 1);
+        assert!(read("").is_empty());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut reader = Reader::new(r#"[{"name":"a"}]"#.as_bytes());
+
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut output = b"[\n".to_vec();
+        let event = SimpleEvent::span(
+            "hello",
+            Duration::from_micros(10),
+            Duration::from_micros(30),
+            false,
+            7,
+            vec![("quote", "\"x\"".to_string())],
+        );
+        event.write_json(&mut output).unwrap();
+        output.extend_from_slice(b",\n");
+        let flow = SimpleEvent {
+            name: "flow",
+            cat: "flow",
+            ph: EventType::FlowEnd,
+            from: Duration::from_micros(20),
+            to: Duration::from_micros(20),
+            id: 5,
+            tid: 7,
+            args: Vec::new(),
+        };
+        flow.write_json(&mut output).unwrap();
+
+        let trace = Trace::read(output.as_slice()).unwrap();
+        let span = trace.span("hello").unwrap();
+        assert_eq!((span.tid, span.from, span.to), (7, Duration::from_micros(10), Duration::from_micros(30)));
+        assert_eq!(span.arg("quote"), Some("\"x\""));
+        assert_eq!(trace.flows[0].id, "5");
+        assert_eq!(trace.flows[0].points[0].span, Some(0));
+    }
+}
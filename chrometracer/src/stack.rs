@@ -0,0 +1,126 @@
+use std::{
+    cell::{Cell, RefCell},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+thread_local! {
+    static STACK: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    static STARTED: Cell<u64> = const { Cell::new(0) };
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Ids of a span being recorded; written to its args as `span_id` and `parent_id`.
+///
+/// Spans entered with [`enter`] are the parent of spans started on the same thread until the
+/// `Entered` is dropped.
+#[derive(Debug)]
+pub struct Entered {
+    id: u64,
+    parent: Option<u64>,
+    pushed: bool,
+}
+
+impl Entered {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn parent(&self) -> Option<u64> {
+        self.parent
+    }
+
+    pub fn args(&self) -> Vec<(&'static str, String)> {
+        let mut args = vec![("span_id", self.id.to_string())];
+        if let Some(parent) = self.parent {
+            args.push(("parent_id", parent.to_string()));
+        }
+        args
+    }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        if self.pushed {
+            // Usually the top, unless guards were dropped out of order.
+            STACK.with(|stack| {
+                let mut stack = stack.borrow_mut();
+                if let Some(position) = stack.iter().rposition(|&id| id == self.id) {
+                    stack.remove(position);
+                }
+            });
+        }
+    }
+}
+
+/// Counts a span starting on the current thread, returning how many have started on it so far.
+pub(crate) fn started() -> u64 {
+    STARTED.with(|started| {
+        started.set(started.get() + 1);
+        started.get()
+    })
+}
+
+fn new(pushed: bool) -> Entered {
+    started();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let parent = STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let parent = stack.last().copied();
+        if pushed {
+            stack.push(id);
+        }
+        parent
+    });
+    Entered { id, parent, pushed }
+}
+
+/// Starts a span on the current thread, nested in the span running so far.
+pub fn enter() -> Entered {
+    new(true)
+}
+
+/// Assigns ids to a span without making it the parent of later spans on this thread.
+///
+/// Used for async spans, whose future may be suspended while other spans run on the thread.
+pub fn enter_detached() -> Entered {
+    new(false)
+}
+
+/// Starts a span on the current thread whose parent is `parent`, possibly running on another
+/// thread, rather than the span running so far.
+pub(crate) fn enter_child_of(parent: u64) -> Entered {
+    started();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    STACK.with(|stack| stack.borrow_mut().push(id));
+    Entered {
+        id,
+        parent: Some(parent),
+        pushed: true,
+    }
+}
+
+/// Id of the innermost span running on the current thread.
+pub fn current_span() -> Option<u64> {
+    STACK.with(|stack| stack.borrow().last().copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nesting() {
+        let outer = enter();
+        let inner = enter();
+        let detached = enter_detached();
+        assert_eq!(inner.parent(), Some(outer.id()));
+        assert_eq!(detached.parent(), Some(inner.id()));
+        assert_eq!(current_span(), Some(inner.id()));
+
+        drop(outer);
+        assert_eq!(current_span(), Some(inner.id()));
+        drop(inner);
+        assert_eq!(current_span(), None);
+    }
+}
@@ -0,0 +1,365 @@
+//! Locks that record how long threads wait for them and how long they are held.
+//!
+//! Every acquisition records a `lock.held` span named by the lock's label that lasts as long as
+//! the guard. When acquiring blocks for at least the lock's threshold, the wait is recorded as a
+//! `lock.wait` span with the same name. `chrometrace contention` summarizes both per label.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{self, LockResult, PoisonError, TryLockError, TryLockResult},
+    time::{Duration, Instant},
+};
+
+use tracing_chrometrace::EventType;
+
+use crate::{current, enter, enter_detached, tracer::start_if, Entered, Level, SimpleEvent};
+
+fn record(name: &'static str, cat: &'static str, from: Duration, to: Duration, mut args: Vec<(&'static str, String)>) {
+    current(|tracer| {
+        if let Some(tracer) = tracer {
+            tracer.trace(SimpleEvent {
+                name,
+                cat,
+                ph: EventType::Complete,
+                from,
+                to,
+                id: 0,
+                tid: tracer.tid,
+                args: std::mem::take(&mut args),
+            });
+        }
+    })
+}
+
+/// Records the `lock.held` span when dropped. Guards drop it before releasing the lock.
+struct Held {
+    label: &'static str,
+    mode: Option<&'static str>,
+    start: Instant,
+    from: Duration,
+    /// Spans started while the lock is held are nested in it.
+    span: Entered,
+}
+
+impl Held {
+    fn new(label: &'static str, mode: Option<&'static str>, start: Instant) -> Held {
+        Held {
+            label,
+            mode,
+            start,
+            from: start.elapsed(),
+            span: enter(),
+        }
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        let mut args = self.span.args();
+        args.extend(self.mode.map(|mode| ("mode", mode.to_string())));
+        record(self.label, "lock.held", self.from, self.start.elapsed(), args);
+    }
+}
+
+fn map_result<G, H>(result: LockResult<G>, f: impl FnOnce(G) -> H) -> LockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(e) => Err(PoisonError::new(f(e.into_inner()))),
+    }
+}
+
+fn map_try_result<G, H>(result: TryLockResult<G>, f: impl FnOnce(G) -> H) -> TryLockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(f(e.into_inner())))),
+        Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+    }
+}
+
+/// Acquires without blocking if possible, and otherwise times the blocking acquisition.
+fn acquire<G>(
+    label: &'static str,
+    mode: Option<&'static str>,
+    threshold: Duration,
+    try_acquire: impl FnOnce() -> TryLockResult<G>,
+    acquire: impl FnOnce() -> LockResult<G>,
+) -> (LockResult<G>, Option<Held>) {
+    let Some(start) = start_if(Level::Info) else {
+        return (acquire(), None);
+    };
+
+    let result = match try_acquire() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::Poisoned(e)) => Err(e),
+        Err(TryLockError::WouldBlock) => {
+            let span = enter_detached();
+            let from = start.elapsed();
+            let result = acquire();
+            let to = start.elapsed();
+            if to - from >= threshold {
+                let mut args = span.args();
+                args.extend(mode.map(|mode| ("mode", mode.to_string())));
+                record(label, "lock.wait", from, to, args);
+            }
+            result
+        }
+    };
+
+    (result, Some(Held::new(label, mode, start)))
+}
+
+fn try_acquire<G>(mode: Option<&'static str>, label: &'static str, result: TryLockResult<G>) -> (TryLockResult<G>, Option<Held>) {
+    let held = match (&result, start_if(Level::Info)) {
+        (Err(TryLockError::WouldBlock), _) | (_, None) => None,
+        (_, Some(start)) => Some(Held::new(label, mode, start)),
+    };
+    (result, held)
+}
+
+/// A [`std::sync::Mutex`] recording waits and hold times.
+pub struct Mutex<T: ?Sized> {
+    label: &'static str,
+    threshold: Duration,
+    inner: sync::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::labeled("mutex", value)
+    }
+
+    /// Creates a mutex whose spans are named `label`.
+    pub const fn labeled(label: &'static str, value: T) -> Self {
+        Mutex {
+            label,
+            threshold: Duration::ZERO,
+            inner: sync::Mutex::new(value),
+        }
+    }
+
+    /// Only record waits lasting at least `threshold`. By default, every wait is recorded.
+    pub fn with_threshold(mut self, threshold: Duration) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let (result, held) = acquire(self.label, None, self.threshold, || self.inner.try_lock(), || self.inner.lock());
+        map_result(result, |inner| MutexGuard { _held: held, inner })
+    }
+
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        let (result, held) = try_acquire(None, self.label, self.inner.try_lock());
+        map_try_result(result, |inner| MutexGuard { _held: held, inner })
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex").field("label", &self.label).field("inner", &&self.inner).finish()
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    // Declared first so the held span ends before the lock is released.
+    _held: Option<Held>,
+    inner: sync::MutexGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// A [`std::sync::RwLock`] recording waits and hold times, with a `mode` arg telling readers
+/// from writers.
+pub struct RwLock<T: ?Sized> {
+    label: &'static str,
+    threshold: Duration,
+    inner: sync::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self::labeled("rwlock", value)
+    }
+
+    /// Creates a lock whose spans are named `label`.
+    pub const fn labeled(label: &'static str, value: T) -> Self {
+        RwLock {
+            label,
+            threshold: Duration::ZERO,
+            inner: sync::RwLock::new(value),
+        }
+    }
+
+    /// Only record waits lasting at least `threshold`. By default, every wait is recorded.
+    pub fn with_threshold(mut self, threshold: Duration) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let (result, held) = acquire(self.label, Some("read"), self.threshold, || self.inner.try_read(), || self.inner.read());
+        map_result(result, |inner| RwLockReadGuard { _held: held, inner })
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let (result, held) = acquire(self.label, Some("write"), self.threshold, || self.inner.try_write(), || self.inner.write());
+        map_result(result, |inner| RwLockWriteGuard { _held: held, inner })
+    }
+
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        let (result, held) = try_acquire(Some("read"), self.label, self.inner.try_read());
+        map_try_result(result, |inner| RwLockReadGuard { _held: held, inner })
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        let (result, held) = try_acquire(Some("write"), self.label, self.inner.try_write());
+        map_try_result(result, |inner| RwLockWriteGuard { _held: held, inner })
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RwLock").field("label", &self.label).field("inner", &&self.inner).finish()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    _held: Option<Held>,
+    inner: sync::RwLockReadGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    _held: Option<Held>,
+    inner: sync::RwLockWriteGuard<'a, T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::capture;
+
+    #[test]
+    fn held() {
+        let mutex = Mutex::labeled("state", 1);
+        let lock = RwLock::labeled("config", ());
+
+        let trace = capture(|| {
+            *mutex.lock().unwrap() += 1;
+            let _read = lock.read().unwrap();
+            assert!(lock.try_write().is_err());
+        });
+
+        assert_eq!(*mutex.lock().unwrap(), 2);
+        assert_eq!(trace.span("state").unwrap().cat, "lock.held");
+        assert_eq!(trace.spans_named("config").count(), 1);
+        assert_eq!(trace.span("config").unwrap().arg("mode"), Some("read"));
+    }
+
+    #[test]
+    fn wait() {
+        let mutex = Mutex::labeled("state", ());
+        let barrier = std::sync::Barrier::new(2);
+
+        // The waiter may get to the lock only once it is released again, so retry until it
+        // was contended.
+        let trace = loop {
+            let guard = mutex.lock().unwrap();
+            let trace = std::thread::scope(|scope| {
+                let waiter = scope.spawn(|| {
+                    capture(|| {
+                        barrier.wait();
+                        drop(mutex.lock().unwrap());
+                    })
+                });
+                barrier.wait();
+                std::thread::sleep(Duration::from_millis(1));
+                drop(guard);
+                waiter.join().unwrap()
+            });
+            if trace.spans.iter().any(|s| s.cat == "lock.wait") {
+                break trace;
+            }
+        };
+
+        let wait = trace.spans.iter().find(|s| s.cat == "lock.wait").unwrap();
+        assert_eq!(wait.name, "state");
+        // Held once the wait is over.
+        let held = trace.spans.iter().find(|s| s.cat == "lock.held").unwrap();
+        assert!(held.from >= wait.to);
+    }
+}
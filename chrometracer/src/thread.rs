@@ -0,0 +1,240 @@
+//! Thread spawning that carries the tracer over to the new thread.
+//!
+//! A spawned thread records into the same session as the thread that spawned it, names its
+//! track after the thread, and runs its closure in a `thread` span. A flow links the short
+//! `spawn` span on the parent to that span, and the `thread` span records the `spawn` span as
+//! its `parent_id`.
+
+use std::{
+    io,
+    thread::{self, JoinHandle, Scope, ScopedJoinHandle},
+    time::Duration,
+};
+
+use tracing_chrometrace::EventType;
+
+use crate::{
+    current, enter_detached, flow_id,
+    stack::enter_child_of,
+    tracer::{install, start_if, ChromeTracer},
+    Entered,
+    Level, SimpleEvent,
+};
+
+fn event(tracer: &ChromeTracer, name: &'static str, ph: EventType, from: Duration, to: Duration, id: u64) -> SimpleEvent {
+    SimpleEvent {
+        name,
+        cat: "thread",
+        ph,
+        from,
+        to,
+        id,
+        tid: tracer.tid,
+        args: Vec::new(),
+    }
+}
+
+struct Parent {
+    tracer: ChromeTracer,
+    flow: u64,
+    from: Duration,
+    span: Entered,
+}
+
+impl Parent {
+    fn new() -> Option<Parent> {
+        let start = start_if(Level::Info)?;
+        let tracer = current(|tracer| tracer.cloned())?;
+        Some(Parent {
+            tracer,
+            flow: flow_id(),
+            from: start.elapsed(),
+            span: enter_detached(),
+        })
+    }
+
+    /// Records the `spawn` span on the parent, once the thread was started.
+    fn spawned(flow: u64, from: Duration, span: Vec<(&'static str, String)>) {
+        current(|tracer| {
+            if let Some(tracer) = tracer {
+                let to = tracer.start.elapsed();
+                let mut spawn = event(tracer, "spawn", EventType::Complete, from, to, 0);
+                spawn.args.clone_from(&span);
+                tracer.trace(spawn);
+                tracer.trace(event(tracer, "spawn", EventType::FlowStart, from, from, flow));
+            }
+        })
+    }
+}
+
+/// Names the track of the calling thread after the thread, if it has a name.
+pub(crate) fn name_current_thread() {
+    let Some(name) = thread::current().name().map(str::to_string) else {
+        return;
+    };
+    current(|tracer| {
+        if let Some(tracer) = tracer {
+            let mut metadata = event(tracer, "thread_name", EventType::Metadata, Duration::ZERO, Duration::ZERO, 0);
+            metadata.args.push(("name", name.clone()));
+            tracer.trace(metadata);
+        }
+    })
+}
+
+/// Wraps the closure of a new thread so it records into the tracer of `parent`.
+fn child<F, T>(parent: Option<Parent>, f: F) -> impl FnOnce() -> T
+where
+    F: FnOnce() -> T,
+{
+    move || {
+        let Some(Parent { tracer, flow, span, .. }) = parent else {
+            return f();
+        };
+
+        install(tracer);
+        let span = enter_child_of(span.id());
+        name_current_thread();
+        let from = current(|tracer| {
+            let tracer = tracer.unwrap();
+            let from = tracer.start.elapsed();
+            tracer.trace(event(tracer, "spawn", EventType::FlowEnd, from, from, flow));
+            from
+        });
+
+        let ret = f();
+
+        current(|tracer| {
+            if let Some(tracer) = tracer {
+                let to = tracer.start.elapsed();
+                tracer.trace(event(tracer, "thread", EventType::Complete, from, to, 0).with_span(&span));
+            }
+        });
+        ret
+    }
+}
+
+/// Like [`std::thread::spawn`].
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f).expect("failed to spawn thread")
+}
+
+/// Like [`std::thread::Scope::spawn`].
+pub fn spawn_scoped<'scope, 'env, F, T>(scope: &'scope Scope<'scope, 'env>, f: F) -> ScopedJoinHandle<'scope, T>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    Builder::new().spawn_scoped(scope, f).expect("failed to spawn thread")
+}
+
+/// Like [`std::thread::Builder`].
+#[derive(Debug)]
+pub struct Builder {
+    inner: thread::Builder,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            inner: thread::Builder::new(),
+        }
+    }
+
+    /// Names the thread, which also names its track in the trace.
+    pub fn name(self, name: String) -> Builder {
+        Builder {
+            inner: self.inner.name(name),
+        }
+    }
+
+    pub fn stack_size(self, size: usize) -> Builder {
+        Builder {
+            inner: self.inner.stack_size(size),
+        }
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let parent = Parent::new();
+        let spawned = parent.as_ref().map(|p| (p.flow, p.from, p.span.args()));
+        let handle = self.inner.spawn(child(parent, f))?;
+        if let Some((flow, from, span)) = spawned {
+            Parent::spawned(flow, from, span);
+        }
+        Ok(handle)
+    }
+
+    pub fn spawn_scoped<'scope, 'env, F, T>(self, scope: &'scope Scope<'scope, 'env>, f: F) -> io::Result<ScopedJoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let parent = Parent::new();
+        let spawned = parent.as_ref().map(|p| (p.flow, p.from, p.span.args()));
+        let handle = self.inner.spawn_scoped(scope, child(parent, f))?;
+        if let Some((flow, from, span)) = spawned {
+            Parent::spawned(flow, from, span);
+        }
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "off"))]
+    use crate::capture;
+
+    #[cfg(not(feature = "off"))]
+    #[crate::instrument]
+    fn work() {}
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn linked() {
+        let trace = capture(|| {
+            Builder::new()
+                .name("worker".to_string())
+                .spawn(work)
+                .unwrap()
+                .join()
+                .unwrap();
+            thread::scope(|scope| {
+                spawn_scoped(scope, work);
+            });
+        });
+
+        assert_eq!(trace.spans_named("work").count(), 2);
+        assert_eq!(trace.flows.len(), 2);
+
+        let work = trace.span("work").unwrap();
+        assert_eq!(trace.parent(work).unwrap().name, "thread");
+        assert_eq!(trace.caller(trace.parent(work).unwrap()).unwrap().name, "spawn");
+        assert_eq!(trace.thread(work.tid).unwrap().name.as_deref(), Some("worker"));
+
+        let points: Vec<&str> = trace.flows[0]
+            .points
+            .iter()
+            .map(|p| trace.spans[p.span.unwrap()].name.as_str())
+            .collect();
+        assert_eq!(points, ["spawn", "thread"]);
+    }
+
+    #[test]
+    fn untraced() {
+        assert_eq!(spawn(|| 1).join().unwrap(), 1);
+    }
+}
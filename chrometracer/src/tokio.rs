@@ -0,0 +1,115 @@
+//! Tokio integration: named worker threads and traced tasks.
+//!
+//! ```ignore
+//! let runtime = tokio::runtime::Builder::new_multi_thread()
+//!     .thread_name_fn(chrometracer::tokio::thread_name)
+//!     .on_thread_start(chrometracer::tokio::on_thread_start)
+//!     .build()?;
+//! runtime.block_on(async {
+//!     chrometracer::tokio::spawn_named("fetch", fetch()).await
+//! });
+//! ```
+//!
+//! Tasks spawned through [`spawn`] are [`Traced`](crate::future::Traced): each poll is a slice on the worker thread
+//! that ran it, wake-ups are flows, and the task's lifetime is an async span. A flow also links
+//! the `spawn` slice to the first poll, so the delay until a task first runs is visible.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use ::tokio::task::JoinHandle;
+use tracing_chrometrace::EventType;
+
+use crate::{
+    enter_detached, flow_id,
+    future::FutureExt,
+    thread::name_current_thread,
+    tracer::{emit, start_if},
+    Level, SimpleEvent,
+};
+
+/// Names worker threads `tokio-worker-0`, `tokio-worker-1`, ..., for
+/// [`tokio::runtime::Builder::thread_name_fn`](::tokio::runtime::Builder::thread_name_fn).
+pub fn thread_name() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!("tokio-worker-{}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Names the track of a runtime thread after the thread, for
+/// [`tokio::runtime::Builder::on_thread_start`](::tokio::runtime::Builder::on_thread_start).
+pub fn on_thread_start() {
+    name_current_thread();
+}
+
+/// Like [`tokio::spawn`], with the task recorded as `task`.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_named("task", future)
+}
+
+/// Like [`tokio::spawn`], with the task recorded as `name`.
+pub fn spawn_named<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let task = future.traced(name);
+    let Some(start) = start_if(Level::Info) else {
+        return ::tokio::spawn(task);
+    };
+
+    let span = enter_detached();
+    let from = start.elapsed();
+    let flow = flow_id();
+    let handle = ::tokio::spawn(task.follows_from(flow));
+    let to = start.elapsed();
+
+    let mut spawn = SimpleEvent::span("spawn", from, to, false, 0, span.args());
+    spawn.cat = "task";
+    emit(spawn);
+    emit(SimpleEvent {
+        name: "wake",
+        cat: "task",
+        ph: EventType::FlowStart,
+        from,
+        to: from,
+        id: flow,
+        tid: 0,
+        args: Vec::new(),
+    });
+    handle
+}
+
+#[cfg(all(test, not(feature = "off")))]
+mod tests {
+    use super::*;
+    use crate::capture;
+
+    #[test]
+    fn tasks() {
+        let runtime = ::tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let trace = capture(|| {
+            runtime.block_on(async {
+                let handle = spawn_named("child", async {
+                    ::tokio::task::yield_now().await;
+                    1
+                });
+                assert_eq!(handle.await.unwrap(), 1);
+            })
+        });
+
+        let polls = trace.spans.iter().filter(|s| s.name == "child" && s.cat == "poll").count();
+        assert_eq!(polls, 2);
+        assert!(trace.spans.iter().any(|s| s.name == "child" && s.is_async));
+
+        let spawn = trace.span("spawn").unwrap();
+        let (flow, _) = trace.flow_points(spawn).next().unwrap();
+        assert_eq!(trace.spans[flow.points[1].span.unwrap()].name, "child");
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Duration,
+};
+
+use tracing_chrometrace::{ChromeEvent, EventType};
+
+/// A recorded trace, either captured in memory or read back from a file.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    /// All spans ordered by start time; enclosing spans come before the spans they contain.
+    pub spans: Vec<Span>,
+    pub threads: Vec<Thread>,
+    pub flows: Vec<Flow>,
+    /// Instant events, as spans starting and ending at the same time, ordered by time.
+    pub instants: Vec<Span>,
+    /// Flow points by the index of the span they bind to, as `(flow, point)` indices.
+    flow_index: HashMap<usize, Vec<(usize, usize)>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub name: String,
+    pub cat: String,
+    pub pid: u64,
+    pub tid: u64,
+    pub from: Duration,
+    pub to: Duration,
+    pub is_async: bool,
+    pub args: BTreeMap<String, String>,
+    /// Index of the enclosing span on the same thread.
+    pub parent: Option<usize>,
+    /// The `span_id` arg, unique within the process that recorded the span.
+    pub id: Option<u64>,
+    /// The `parent_id` arg: the span that was running when this one started, possibly on
+    /// another thread.
+    pub parent_id: Option<u64>,
+}
+
+/// Arrows linking causally related spans, possibly across threads and processes.
+#[derive(Clone, Debug)]
+pub struct Flow {
+    pub id: String,
+    pub name: String,
+    pub cat: String,
+    /// The start, steps and end of the flow in the order they happened.
+    pub points: Vec<FlowPoint>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlowPoint {
+    pub ph: EventType,
+    pub pid: u64,
+    pub tid: u64,
+    pub ts: Duration,
+    /// Index of the span the point binds to. Flow starts, and points with `"bp": "e"`, bind to
+    /// the innermost span enclosing them; other points bind to the next span to start on the
+    /// same thread, as in Chrome. Either falls back to the other when there is no such span.
+    pub span: Option<usize>,
+    enclosing: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Thread {
+    pub pid: u64,
+    pub tid: u64,
+    pub name: Option<String>,
+}
+
+impl Span {
+    pub fn duration(&self) -> Duration {
+        self.to.saturating_sub(self.from)
+    }
+
+    pub fn arg(&self, key: &str) -> Option<&str> {
+        self.args.get(key).map(String::as_str)
+    }
+}
+
+pub(crate) fn timestamp(ts: f64) -> Duration {
+    Duration::from_nanos((ts.max(0.0) * 1000.0).round() as u64)
+}
+
+impl Trace {
+    pub fn from_events<I>(events: I) -> Trace
+    where
+        I: IntoIterator<Item = ChromeEvent>,
+    {
+        let mut spans = Vec::new();
+        let mut names = HashMap::new();
+        let mut durations: HashMap<(u64, u64), Vec<ChromeEvent>> = HashMap::new();
+        let mut asyncs: HashMap<(u64, String, String), ChromeEvent> = HashMap::new();
+        let mut flows: Vec<Flow> = Vec::new();
+        let mut open_flows: HashMap<(String, String), usize> = HashMap::new();
+        let mut instants = Vec::new();
+
+        let span = |begin: ChromeEvent, to: f64, is_async: bool| Span {
+            from: timestamp(begin.ts),
+            to: timestamp(to),
+            name: begin.name.into_owned(),
+            cat: begin.cat.into_owned(),
+            pid: begin.pid,
+            tid: begin.tid,
+            is_async,
+            id: begin.args.get("span_id").and_then(|id| id.parse().ok()),
+            parent_id: begin.args.get("parent_id").and_then(|id| id.parse().ok()),
+            args: begin.args.into_iter().collect(),
+            parent: None,
+        };
+
+        for event in events {
+            match event.ph {
+                EventType::Complete => {
+                    let to = event.ts + event.dur.unwrap_or_default();
+                    spans.push(span(event, to, false));
+                }
+                EventType::DurationBegin => {
+                    durations.entry((event.pid, event.tid)).or_default().push(event);
+                }
+                EventType::DurationEnd => {
+                    if let Some(begin) = durations.get_mut(&(event.pid, event.tid)).and_then(Vec::pop) {
+                        spans.push(span(begin, event.ts, false));
+                    }
+                }
+                EventType::AsyncStart => {
+                    asyncs.insert((event.pid, event.cat.to_string(), event.id.to_string()), event);
+                }
+                EventType::AsyncEnd => {
+                    if let Some(begin) = asyncs.remove(&(event.pid, event.cat.to_string(), event.id.to_string())) {
+                        spans.push(span(begin, event.ts, true));
+                    }
+                }
+                EventType::FlowStart | EventType::FlowStep | EventType::FlowEnd => {
+                    let key = (event.cat.to_string(), event.id.to_string());
+                    let index = match open_flows.get(&key) {
+                        Some(&index) if event.ph != EventType::FlowStart => index,
+                        _ => {
+                            flows.push(Flow {
+                                id: key.1.clone(),
+                                name: event.name.to_string(),
+                                cat: key.0.clone(),
+                                points: Vec::new(),
+                            });
+                            open_flows.insert(key.clone(), flows.len() - 1);
+                            flows.len() - 1
+                        }
+                    };
+                    flows[index].points.push(FlowPoint {
+                        ph: event.ph,
+                        pid: event.pid,
+                        tid: event.tid,
+                        ts: timestamp(event.ts),
+                        span: None,
+                        enclosing: event.ph == EventType::FlowStart || event.args.get(crate::reader::BP_ARG).is_some_and(|bp| bp == "e"),
+                    });
+                    if event.ph == EventType::FlowEnd {
+                        open_flows.remove(&key);
+                    }
+                }
+                EventType::Instant => {
+                    let ts = event.ts;
+                    instants.push(span(event, ts, false));
+                }
+                EventType::Metadata if event.name == "thread_name" => {
+                    if let Some(name) = event.args.get("name") {
+                        names.insert((event.pid, event.tid), name.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Span ids grow as spans are entered, so they order spans with identical timestamps.
+        spans.sort_by_key(|s| (s.from, Reverse(s.to), s.pid, s.tid, s.id));
+        instants.sort_by_key(|s| (s.from, s.pid, s.tid));
+
+        let mut stacks: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        for index in 0..spans.len() {
+            if spans[index].is_async {
+                continue;
+            }
+
+            let stack = stacks.entry((spans[index].pid, spans[index].tid)).or_default();
+            while let Some(&top) = stack.last() {
+                if spans[top].from <= spans[index].from && spans[index].to <= spans[top].to {
+                    break;
+                }
+                stack.pop();
+            }
+            spans[index].parent = stack.last().copied();
+            stack.push(index);
+        }
+
+        // Explicit ids take precedence over timestamps where both apply.
+        let ids: HashMap<(u64, u64), usize> = spans
+            .iter()
+            .enumerate()
+            .filter_map(|(index, s)| Some(((s.pid, s.id?), index)))
+            .collect();
+        for index in 0..spans.len() {
+            let span = &spans[index];
+            let Some(&parent) = span.parent_id.and_then(|id| ids.get(&(span.pid, id))) else {
+                continue;
+            };
+            if parent < index && spans[parent].tid == span.tid && !spans[parent].is_async && !span.is_async {
+                spans[index].parent = Some(parent);
+            }
+        }
+
+        // Spans of each thread in order of their start, like `spans`.
+        let mut threads: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+        for (index, span) in spans.iter().enumerate().filter(|(_, s)| !s.is_async) {
+            threads.entry((span.pid, span.tid)).or_default().push(index);
+        }
+        let mut flow_index: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        for (f, flow) in flows.iter_mut().enumerate() {
+            for (p, point) in flow.points.iter_mut().enumerate() {
+                let Some(thread) = threads.get(&(point.pid, point.tid)) else {
+                    continue;
+                };
+                let started = thread.partition_point(|&i| spans[i].from <= point.ts);
+                // The innermost enclosing span is the last one started or one of its parents.
+                let enclosing = || {
+                    let mut candidate = thread[..started].last().copied();
+                    while let Some(index) = candidate.filter(|&i| spans[i].to < point.ts) {
+                        candidate = spans[index].parent;
+                    }
+                    candidate
+                };
+                let next = || {
+                    let first = thread.partition_point(|&i| spans[i].from < point.ts);
+                    thread.get(first).copied()
+                };
+                point.span = if point.enclosing {
+                    enclosing().or_else(next)
+                } else {
+                    next().or_else(enclosing)
+                };
+                if let Some(span) = point.span {
+                    flow_index.entry(span).or_default().push((f, p));
+                }
+            }
+        }
+
+        let threads = spans
+            .iter()
+            .map(|s| (s.pid, s.tid))
+            .chain(names.keys().copied())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|(pid, tid)| Thread {
+                pid,
+                tid,
+                name: names.get(&(pid, tid)).cloned(),
+            })
+            .collect();
+
+        Trace {
+            spans,
+            threads,
+            flows,
+            instants,
+            flow_index,
+        }
+    }
+
+    pub fn spans_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Span> + 'a {
+        self.spans.iter().filter(move |s| s.name == name)
+    }
+
+    /// The first span called `name`.
+    pub fn span(&self, name: &str) -> Option<&Span> {
+        self.spans.iter().find(|s| s.name == name)
+    }
+
+    pub fn parent(&self, span: &Span) -> Option<&Span> {
+        span.parent.map(|index| &self.spans[index])
+    }
+
+    /// The span that was running when `span` started, following its `parent_id` arg, even
+    /// to another thread.
+    pub fn caller(&self, span: &Span) -> Option<&Span> {
+        let parent_id = span.parent_id?;
+        self.spans.iter().find(|s| s.pid == span.pid && s.id == Some(parent_id))
+    }
+
+    pub fn ancestors<'a>(&'a self, span: &'a Span) -> impl Iterator<Item = &'a Span> + 'a {
+        std::iter::successors(self.parent(span), move |s| self.parent(s))
+    }
+
+    /// Index of `span` in `spans`, if it is one of them.
+    fn index_of(&self, span: &Span) -> Option<usize> {
+        let offset = (span as *const Span as usize).checked_sub(self.spans.as_ptr() as usize)?;
+        let index = offset / std::mem::size_of::<Span>();
+        (index < self.spans.len() && std::ptr::eq(&self.spans[index], span)).then_some(index)
+    }
+
+    pub fn children<'a>(&'a self, span: &'a Span) -> impl Iterator<Item = &'a Span> + 'a {
+        let index = self.index_of(span);
+        self.spans
+            .iter()
+            .filter(move |s| index.is_some() && s.parent == index)
+    }
+
+    /// Whether `inner` runs nested inside `outer`, at any depth.
+    pub fn contains(&self, outer: &Span, inner: &Span) -> bool {
+        self.ancestors(inner).any(|s| std::ptr::eq(s, outer))
+    }
+
+    /// Flow points bound to `span`, along with the flow they belong to.
+    pub fn flow_points<'a>(&'a self, span: &'a Span) -> impl Iterator<Item = (&'a Flow, usize)> + 'a {
+        let points = self.index_of(span).and_then(|index| self.flow_index.get(&index));
+        points
+            .into_iter()
+            .flatten()
+            .map(move |&(flow, point)| (&self.flows[flow], point))
+    }
+
+    pub fn thread(&self, tid: u64) -> Option<&Thread> {
+        self.threads.iter().find(|t| t.tid == tid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "off"))]
+    use std::time::Duration;
+
+    #[cfg(not(feature = "off"))]
+    #[crate::instrument]
+    fn inner() {}
+
+    #[cfg(not(feature = "off"))]
+    #[crate::instrument(fields(n = 3))]
+    fn outer() {
+        inner();
+        inner();
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn capture() {
+        let trace = crate::capture(outer);
+
+        assert_eq!(trace.spans.len(), 3);
+        let outer = trace.span("outer").unwrap();
+        assert_eq!(outer.arg("n"), Some("3"));
+        assert_eq!(trace.children(outer).count(), 2);
+        assert!(trace.spans_named("inner").all(|s| trace.contains(outer, s)));
+        assert!(trace.spans_named("inner").all(|s| s.parent_id == outer.id));
+        assert_eq!(trace.threads.len(), 1);
+    }
+
+    #[test]
+    fn explicit_ids() {
+        let trace = crate::Trace::read(
+            r#"[
+            {"name":"inner","ph":"X","ts":0,"dur":10,"pid":1,"tid":1,"args":{"span_id":"2","parent_id":"1"}},
+            {"name":"outer","ph":"X","ts":0,"dur":10,"pid":1,"tid":1,"args":{"span_id":"1"}},
+            {"name":"worker","ph":"X","ts":5,"dur":10,"pid":1,"tid":2,"args":{"span_id":"3","parent_id":"2"}}
+        ]"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let inner = trace.span("inner").unwrap();
+        assert_eq!(trace.parent(inner).unwrap().name, "outer");
+
+        let worker = trace.span("worker").unwrap();
+        assert!(trace.parent(worker).is_none());
+        assert_eq!(trace.caller(worker).unwrap().name, "inner");
+    }
+
+    #[test]
+    fn flows() {
+        let trace = crate::Trace::read(
+            r#"[
+            {"name":"send","ph":"X","ts":0,"dur":10,"pid":1,"tid":1},
+            {"name":"recv","ph":"X","ts":20,"dur":10,"pid":1,"tid":2},
+            {"name":"msg","cat":"c","id":7,"ph":"s","ts":5,"pid":1,"tid":1},
+            {"name":"msg","cat":"c","id":7,"ph":"f","bp":"e","ts":25,"pid":1,"tid":2}
+        ]"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(trace.flows.len(), 1);
+        let recv = trace.span("recv").unwrap();
+        let (flow, point) = trace.flow_points(recv).next().unwrap();
+        assert_eq!(point, 1);
+        assert_eq!(trace.spans[flow.points[0].span.unwrap()].name, "send");
+    }
+
+    #[test]
+    fn binding_points() {
+        let trace = crate::Trace::read(
+            r#"[
+            {"name":"send","ph":"X","ts":0,"dur":10,"pid":1,"tid":1},
+            {"name":"handle","ph":"X","ts":20,"dur":10,"pid":1,"tid":2},
+            {"name":"later","ph":"X","ts":40,"dur":10,"pid":1,"tid":2},
+            {"name":"a","cat":"c","id":1,"ph":"s","ts":5,"pid":1,"tid":1},
+            {"name":"a","cat":"c","id":1,"ph":"f","ts":25,"pid":1,"tid":2},
+            {"name":"b","cat":"c","id":2,"ph":"s","ts":5,"pid":1,"tid":1},
+            {"name":"b","cat":"c","id":2,"ph":"f","bp":"e","ts":25,"pid":1,"tid":2}
+        ]"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let bound = |name: &str| {
+            let flow = trace.flows.iter().find(|f| f.name == name).unwrap();
+            trace.spans[flow.points[1].span.unwrap()].name.as_str()
+        };
+        // Without a binding point, the end binds to the next slice to start.
+        assert_eq!(bound("a"), "later");
+        assert_eq!(bound("b"), "handle");
+        assert_eq!(trace.flow_points(trace.span("send").unwrap()).count(), 2);
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn nested_capture() {
+        let trace = crate::capture(|| {
+            let inner = crate::capture(inner);
+            assert_eq!(inner.spans.len(), 1);
+            outer();
+        });
+
+        assert_eq!(trace.spans.len(), 3);
+        assert!(trace.spans.iter().all(|s| s.duration() < Duration::from_secs(1)));
+    }
+}
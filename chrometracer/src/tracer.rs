@@ -1,52 +1,174 @@
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use crossbeam_queue::ArrayQueue;
 use derive_builder::Builder;
 use std::{
     cell::RefCell,
     fs::File,
     io::{self, BufWriter, Write},
-    thread::{self, JoinHandle, ThreadId},
-    time::Instant, os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 use tracing_chrometrace::{ChromeEvent, ChromeEventBuilder, EventType};
 
+use crate::control::Recorder;
+use crate::level::{self, Level, LevelFilter};
+use crate::stack::Entered;
+use crate::trigger::Trigger;
+use crate::trace::Trace;
+use crate::wire;
 
-
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SimpleEvent {
     pub name: &'static str,
+    pub cat: &'static str,
+    /// `Complete` for spans, `AsyncStart` for async spans written as a begin/end pair, or the
+    /// phase of an event happening at `from`, such as the points of a flow.
+    pub ph: EventType,
     pub from: std::time::Duration,
     pub to: std::time::Duration,
-    pub is_async: bool,
+    /// Ties together the begin and end of async spans and the points of a flow.
+    pub id: u64,
     pub tid: u64,
+    pub args: Vec<(&'static str, String)>,
 }
 
 impl SimpleEvent {
-    fn write_json<W>(self, writer: &mut W)
+    /// A span, written as a begin/end pair when `is_async`, like the `is_async` field this
+    /// replaces did.
+    pub fn span(
+        name: &'static str,
+        from: std::time::Duration,
+        to: std::time::Duration,
+        is_async: bool,
+        tid: u64,
+        args: Vec<(&'static str, String)>,
+    ) -> Self {
+        SimpleEvent {
+            name,
+            cat: if is_async { "async" } else { "" },
+            ph: if is_async { EventType::AsyncStart } else { EventType::Complete },
+            from,
+            to,
+            id: if is_async { from.as_nanos() as u64 } else { 0 },
+            tid,
+            args,
+        }
+    }
+
+    /// Adds the `span_id` and `parent_id` args.
+    pub fn with_span(mut self, span: &Entered) -> Self {
+        self.args.extend(span.args());
+        self
+    }
+
+    /// Whether this is an async span, formerly the `is_async` field.
+    pub fn is_async(&self) -> bool {
+        self.ph == EventType::AsyncStart
+    }
+
+    fn is_flow(&self) -> bool {
+        matches!(self.ph, EventType::FlowStart | EventType::FlowStep | EventType::FlowEnd)
+    }
+
+    pub(crate) fn write_json<W>(self, writer: &mut W) -> io::Result<()>
     where
         W: std::io::Write
     {
         let pid = std::process::id();
-        let json = if self.is_async {
-            let begin = self.from.as_nanos() as f64 / 1000.0;
-            let end = self.to.as_nanos() as f64 / 1000.0;
-            format!("{{\"name\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{},\"id\":{},\"ph\":\"b\",\"cat\":\"async\"}},\n{{\"name\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{},\"id\":{},\"ph\":\"e\",\"cat\":\"async\"}}", self.name, begin, pid, self.tid, self.from.as_nanos(), self.name, end, pid, self.tid, self.from.as_nanos())
-            
-        } else {
-            let ts = self.from.as_nanos() as f64 / 1000.0;
-            let dur = (self.to.as_nanos() - self.from.as_nanos()) as f64 / 1000.0;
-            format!("{{\"name\":\"{}\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{},\"ph\":\"X\"}}", self.name, ts, dur, std::process::id(), self.tid)
+        let args = self.args_json();
+        let json = match self.ph {
+            EventType::AsyncStart => {
+                let begin = self.from.as_nanos() as f64 / 1000.0;
+                let end = self.to.as_nanos() as f64 / 1000.0;
+                format!("{{\"name\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{},\"id\":{},\"ph\":\"b\",\"cat\":\"{}\"{}}},\n{{\"name\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{},\"id\":{},\"ph\":\"e\",\"cat\":\"{}\"}}", self.name, begin, pid, self.tid, self.id, self.cat, args, self.name, end, pid, self.tid, self.id, self.cat)
+            }
+            EventType::Complete => {
+                let ts = self.from.as_nanos() as f64 / 1000.0;
+                let dur = (self.to.as_nanos() - self.from.as_nanos()) as f64 / 1000.0;
+                let cat = if self.cat.is_empty() { String::new() } else { format!(",\"cat\":\"{}\"", self.cat) };
+                format!("{{\"name\":\"{}\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{},\"ph\":\"X\"{}{}}}", self.name, ts, dur, pid, self.tid, cat, args)
+            }
+            ph => {
+                let ts = self.from.as_nanos() as f64 / 1000.0;
+                // Flow points bind to the slice enclosing them rather than the next one.
+                let bp = if self.is_flow() { ",\"bp\":\"e\"" } else { "" };
+                let id = if self.is_flow() || self.id != 0 { format!(",\"id\":{}", self.id) } else { String::new() };
+                format!("{{\"name\":\"{}\",\"cat\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{}{},\"ph\":{}{}{}}}", self.name, self.cat, ts, pid, self.tid, id, serde_json::to_value(ph).unwrap(), bp, args)
+            }
+        };
+        writer.write_all(json.as_bytes())
+    }
+
+    fn args_json(&self) -> String {
+        if self.args.is_empty() {
+            return String::new();
+        }
+
+        let args = self
+            .args
+            .iter()
+            .map(|(key, value)| format!("{}:{}", serde_json::Value::from(*key), serde_json::Value::from(value.as_str())))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(",\"args\":{{{}}}", args)
+    }
+
+    /// Converts into the equivalent Chrome events; async events become a begin/end pair.
+    pub fn to_chrome_events(&self, pid: u64) -> Vec<ChromeEvent> {
+        let event = |ph: EventType, ts: std::time::Duration| {
+            let mut builder = ChromeEvent::builder(SystemTime::UNIX_EPOCH);
+            builder
+                .name(self.name)
+                .cat(self.cat)
+                .ph(ph)
+                .ts(ts.as_nanos() as f64 / 1000.0)
+                .pid(pid)
+                .tid(self.tid);
+            builder
         };
-        writer.write_all(json.as_bytes()).unwrap();
-    } 
+
+        match self.ph {
+            EventType::AsyncStart => {
+                let id = self.id.to_string();
+                let mut begin = event(EventType::AsyncStart, self.from);
+                begin.id(id.clone());
+                for (key, value) in &self.args {
+                    begin.arg((key.to_string(), value.clone()));
+                }
+                let mut end = event(EventType::AsyncEnd, self.to);
+                end.id(id);
+
+                vec![begin.build().unwrap(), end.build().unwrap()]
+            }
+            ph => {
+                let mut single = event(ph, self.from);
+                if ph == EventType::Complete {
+                    single.dur(Some((self.to - self.from).as_nanos() as f64 / 1000.0));
+                } else if self.is_flow() || self.id != 0 {
+                    single.id(self.id.to_string());
+                }
+                if self.is_flow() {
+                    // Bound to the enclosing slice, as written by `write_json`.
+                    single.arg((crate::reader::BP_ARG.to_string(), "e".to_string()));
+                }
+                for (key, value) in &self.args {
+                    single.arg((key.to_string(), value.clone()));
+                }
+
+                vec![single.build().unwrap()]
+            }
+        }
+    }
 }
 
 
 thread_local! {
-    static CURRENT: RefCell<Option<ChromeTracer>> = RefCell::new(None);
+    static CURRENT: RefCell<Option<ChromeTracer>> = const { RefCell::new(None) };
 }
 
-static mut GLOBAL: Option<ChromeTracer> = None;
+static GLOBAL: OnceLock<ChromeTracer> = OnceLock::new();
 
 #[derive(Builder, Clone)]
 #[builder(custom_constructor, build_fn(private, name = "_build"))]
@@ -59,10 +181,88 @@ pub struct ChromeTracer {
 
     #[builder(default = "std::thread::current().id().as_u64().into()")]
     pub tid: u64,
+
+    /// Runtime level filter installed on `init`, read from `CHROMETRACER_LEVEL` by default.
+    #[builder(setter(strip_option), default = "env_level()")]
+    max_level: Option<LevelFilter>,
+
+    /// File the trace is written to, read from `CHROMETRACER_FILE` by default, or `trace.json`.
+    /// Children started through [`crate::process::CommandExt`] do not inherit the variable.
+    #[builder(setter(into), default = "env_path()")]
+    path: PathBuf,
+
+    /// Unix socket of a `chrometrace-collector` to stream events to instead of writing `path`,
+    /// read from `CHROMETRACER_SOCKET` by default. While streaming, `control` and `triggers`
+    /// are not used.
+    #[builder(setter(into, strip_option), default = "env_socket()")]
+    socket: Option<PathBuf>,
+
+    /// Unix socket to accept [`crate::control`] requests on, read from `CHROMETRACER_CONTROL` by
+    /// default. Events are then kept in memory, and only written by captures and dumps.
+    #[builder(setter(into, strip_option), default = "env_control()")]
+    control: Option<PathBuf>,
+
+    /// Number of recent events the flight recorder of a controlled or triggered tracer keeps.
+    #[builder(default = "100_000")]
+    flight_recorder: usize,
+
+    /// Conditions that save the history of the flight recorder to a file when they fire; events
+    /// are then kept in memory as with `control`. See [`crate::trigger`].
+    #[builder(setter(each(name = "trigger")), default)]
+    triggers: Vec<Trigger>,
+
+    /// Minimum time between two triggered captures, one minute by default.
+    #[builder(default = "Duration::from_secs(60)")]
+    trigger_interval: Duration,
+
+    /// Most triggered captures written over the life of the tracer, 10 by default.
+    #[builder(default = "10")]
+    max_triggered: usize,
+
+    /// Directory triggered captures, and captures and dumps not given a path, are written to;
+    /// the working directory by default.
+    #[builder(setter(into), default)]
+    output_dir: PathBuf,
+
+    /// Spans shorter than this are dropped before being written, unless they are an ancestor
+    /// of a longer span or a direct child of one.
+    #[builder(setter(strip_option), default)]
+    min_duration: Option<Duration>,
+
+    /// Polls of traced futures lasting at least this long are flagged with an instant event.
+    /// See [`crate::future`].
+    #[builder(setter(strip_option), default)]
+    pub(crate) long_poll: Option<Duration>,
+
+    /// Whether long polls also record a backtrace of the code that polled the future, taken
+    /// once the poll has returned. It shows which task and executor ran the poll, not the
+    /// blocking call inside it.
+    #[builder(default)]
+    pub(crate) long_poll_backtrace: bool,
+
+    /// Whether the futures with long polls are listed on stderr when the guard is dropped.
+    #[builder(default)]
+    long_poll_summary: bool,
+}
+
+fn env_level() -> Option<LevelFilter> {
+    std::env::var(crate::process::LEVEL_VAR).ok()?.parse().ok()
+}
+
+fn env_socket() -> Option<PathBuf> {
+    std::env::var_os(crate::process::SOCKET_VAR).map(PathBuf::from)
+}
+
+fn env_control() -> Option<PathBuf> {
+    std::env::var_os(crate::process::CONTROL_VAR).map(PathBuf::from)
+}
+
+fn env_path() -> PathBuf {
+    std::env::var_os(crate::process::FILE_VAR).map_or_else(|| "trace.json".into(), PathBuf::from)
 }
 
 #[allow(clippy::large_enum_variant)]
-enum ChromeTracerMessage {
+pub(crate) enum ChromeTracerMessage {
     ChromeEvent(SimpleEvent/* , ThreadId*/),
     Terminate,
 }
@@ -70,25 +270,42 @@ enum ChromeTracerMessage {
 pub struct ChromeTracerGuard {
     sender: Sender<ChromeTracerMessage>,
     handle: Option<JoinHandle<()>>,
+    long_poll: Option<Duration>,
+    control: Option<PathBuf>,
 }
 
 impl Drop for ChromeTracerGuard {
     fn drop(&mut self) {
-        self.sender.send(ChromeTracerMessage::Terminate).unwrap();
+        let _ = self.sender.send(ChromeTracerMessage::Terminate);
         self.handle.take().map(JoinHandle::join).unwrap().unwrap();
+
+        if let Some(control) = &self.control {
+            let _ = std::fs::remove_file(control);
+        }
+
+        let polls = crate::future::take_long_polls();
+        if let Some(threshold) = self.long_poll {
+            let _ = crate::future::write_long_polls(&mut std::io::stderr(), threshold, &polls);
+        }
     }
 }
 
 impl ChromeTracerBuilder {
     pub fn init(&self) -> ChromeTracerGuard {
         CURRENT.with(|c| {
-            if unsafe { GLOBAL.is_some() } {
+            if GLOBAL.get().is_some() {
                 panic!("Unable to intialize ChromeTracer. A chrometracer already been set");
             } else {
                 let mut tracer = self._build().expect("All required fields were initialized");
                 let guard = tracer.init();
 
-                unsafe { GLOBAL = Some(tracer.clone()) };
+                if let Some(max_level) = tracer.max_level {
+                    level::set_max_level(max_level);
+                }
+
+                if GLOBAL.set(tracer.clone()).is_err() {
+                    panic!("Unable to intialize ChromeTracer. A chrometracer already been set");
+                }
                 *c.borrow_mut() = Some(tracer);
 
                 guard
@@ -101,34 +318,230 @@ pub fn builder() -> ChromeTracerBuilder {
     ChromeTracerBuilder::create_empty()
 }
 
-impl ChromeTracer {
-    fn init(&mut self) -> ChromeTracerGuard {
-        let (sender, receiver) = crossbeam_channel::unbounded();
-        self.sender = Some(sender.clone());
+/// Reads `CLOCK_MONOTONIC`, the clock behind [`Instant`] on Unix.
+#[cfg(unix)]
+fn monotonic_now() -> Option<Duration> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is a valid timespec to write to.
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+fn monotonic_now() -> Option<Duration> {
+    None
+}
+
+/// `CLOCK_MONOTONIC` and `CLOCK_REALTIME` in nanoseconds at `start`, so traces of several
+/// processes can be aligned.
+pub fn clock_anchor(start: Instant) -> (Option<u64>, Option<u64>) {
+    let elapsed = start.elapsed();
+    let monotonic = monotonic_now().map(|now| now.saturating_sub(elapsed).as_nanos() as u64);
+    let realtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|now| now.saturating_sub(elapsed).as_nanos() as u64);
+    (monotonic, realtime)
+}
+
+fn clock_anchor_event(start: Instant, tid: u64) -> SimpleEvent {
+    let (monotonic, realtime) = clock_anchor(start);
+    let mut args = Vec::new();
+    if let Some(monotonic) = monotonic {
+        args.push(("monotonic_ns", monotonic.to_string()));
+    }
+    if let Some(realtime) = realtime {
+        args.push(("realtime_ns", realtime.to_string()));
+    }
+    SimpleEvent {
+        name: "clock_anchor",
+        cat: "",
+        ph: EventType::Metadata,
+        from: Duration::ZERO,
+        to: Duration::ZERO,
+        id: 0,
+        tid,
+        args,
+    }
+}
+
+fn write_events(path: PathBuf, receiver: Receiver<ChromeTracerMessage>) {
+    if let Err(e) = write_trace(&path, &receiver) {
+        eprintln!("chrometracer: cannot write {}: {}", path.display(), e);
+        // Keep taking events until the guard is dropped, so the process is not affected.
+        while let Ok(ChromeTracerMessage::ChromeEvent(_)) = receiver.recv() {}
+    }
+}
+
+fn write_trace(path: &Path, receiver: &Receiver<ChromeTracerMessage>) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    let queue = ArrayQueue::new(1);
+
+    writer.write_all(b"[\n")?;
 
-        let handle = Some(thread::spawn(move || {
-            let mut writer = BufWriter::new(File::create("trace.json").unwrap());
+    while let Ok(ChromeTracerMessage::ChromeEvent(event)) = receiver.recv() {
+        if let Some(e) = queue.force_push(event) {
+            e.write_json(&mut writer)?;
+            writer.write_all(b",\n")?;
+        };
+    }
+
+    if let Some(e) = queue.pop() {
+        e.write_json(&mut writer)?;
+        writer.write_all(b"\n")?;
+    }
+
+    writer.write_all(b"]")?;
+    writer.flush()
+}
+
+/// Streams events to a collector, flushing whenever no more are queued so a crash loses little.
+fn stream_events(mut writer: Box<dyn Write + Send>, receiver: Receiver<ChromeTracerMessage>) {
+    let pid = std::process::id().into();
+    let mut failed = false;
+    while let Ok(ChromeTracerMessage::ChromeEvent(event)) = receiver.recv() {
+        if failed {
+            continue;
+        }
+        let result = event
+            .to_chrome_events(pid)
+            .iter()
+            .try_for_each(|e| wire::write_event(&mut writer, e))
+            .and_then(|()| if receiver.is_empty() { writer.flush() } else { Ok(()) });
+        if let Err(e) = result {
+            eprintln!("chrometracer: lost the connection to the collector: {}", e);
+            failed = true;
+        }
+    }
+    let _ = writer.flush();
+}
 
-            let queue = ArrayQueue::new(1);
+/// Keeps events in the flight recorder of a controlled tracer, ending captures on time even
+/// when no events arrive.
+fn record_events(recorder: Arc<Mutex<Recorder>>, receiver: Receiver<ChromeTracerMessage>) {
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(ChromeTracerMessage::ChromeEvent(event)) => recorder.lock().unwrap().record(event),
+            Err(RecvTimeoutError::Timeout) => recorder.lock().unwrap().expire(),
+            Ok(ChromeTracerMessage::Terminate) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    let mut recorder = recorder.lock().unwrap();
+    for (path, events) in [recorder.stop(), recorder.stop_triggered()].into_iter().flatten() {
+        eprintln!("chrometracer: wrote {} events to {}", events, path.display());
+    }
+}
 
-            writer.write_all(b"[\n").unwrap();
+impl ChromeTracer {
+    /// The flight recorder, if events are to be kept in memory, answering requests on the
+    /// control socket if one is set. Returns whether it is listening.
+    fn recorder(&self) -> Option<(Arc<Mutex<Recorder>>, bool)> {
+        let recorder = Recorder::new(self.flight_recorder)
+            .in_dir(self.output_dir.clone())
+            .with_triggers(self.triggers.clone(), self.trigger_interval, self.max_triggered);
+        let recorder = Arc::new(Mutex::new(recorder));
+        let listening = self.listen(&recorder);
+        (listening || !self.triggers.is_empty()).then_some((recorder, listening))
+    }
 
-            while let Ok(ChromeTracerMessage::ChromeEvent(event)) = receiver.recv() {
-                if let Some(e) = queue.force_push(event) {
-                    e.write_json(&mut writer);
-                    writer.write_all(b",\n").unwrap();
-                };
+    #[cfg(unix)]
+    fn listen(&self, recorder: &Arc<Mutex<Recorder>>) -> bool {
+        let Some(control) = &self.control else {
+            return false;
+        };
+        match crate::control::listen(control, recorder.clone()) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("chrometracer: cannot listen on {}: {}", control.display(), e);
+                false
             }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn listen(&self, _recorder: &Arc<Mutex<Recorder>>) -> bool {
+        if self.control.is_some() {
+            eprintln!("chrometracer: control sockets are only supported on Unix");
+        }
+        false
+    }
 
-            if let Some(e) = queue.pop() {
-                e.write_json(&mut writer);
-                writer.write_all(b"\n").unwrap();
+    /// Connects to the collector at `socket`, if set, and introduces the process to it.
+    #[cfg(unix)]
+    fn connect(&self) -> Option<Box<dyn Write + Send>> {
+        let socket = self.socket.as_ref()?;
+        let connected = std::os::unix::net::UnixStream::connect(socket).and_then(|stream| {
+            let mut writer = BufWriter::new(stream);
+            let (monotonic_ns, realtime_ns) = clock_anchor(self.start);
+            let name = std::env::current_exe()
+                .ok()
+                .and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()))
+                .unwrap_or_default();
+            let hello = wire::Hello {
+                pid: std::process::id().into(),
+                name,
+                monotonic_ns,
+                realtime_ns,
+            };
+            wire::write_hello(&mut writer, &hello)?;
+            Ok(writer)
+        });
+
+        match connected {
+            Ok(writer) => Some(Box::new(writer)),
+            Err(e) => {
+                eprintln!(
+                    "chrometracer: cannot connect to the collector at {}: {}; writing {} instead",
+                    socket.display(),
+                    e,
+                    self.path.display()
+                );
+                None
             }
+        }
+    }
 
-            writer.write_all(b"]").unwrap();
+    #[cfg(not(unix))]
+    fn connect(&self) -> Option<Box<dyn Write + Send>> {
+        if self.socket.is_some() {
+            eprintln!("chrometracer: collectors are only supported on Unix; writing {} instead", self.path.display());
+        }
+        None
+    }
+
+    fn init(&mut self) -> ChromeTracerGuard {
+        crate::future::take_long_polls();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.sender = Some(sender.clone());
+        self.trace(clock_anchor_event(self.start, self.tid));
+
+        let receiver = match self.min_duration {
+            Some(min) => crate::filter::relay(min, receiver),
+            None => receiver,
+        };
+        let path = self.path.clone();
+        let socket = self.connect();
+        if socket.is_some() && (self.control.is_some() || !self.triggers.is_empty()) {
+            eprintln!("chrometracer: streaming to a collector; the control socket and triggers are not used");
+        }
+        let recorder = if socket.is_none() { self.recorder() } else { None };
+        let control = recorder.as_ref().filter(|(_, listening)| *listening).and(self.control.clone());
+        let recorder = recorder.map(|(recorder, _)| recorder);
+        let handle = Some(thread::spawn(move || match (socket, recorder) {
+            (Some(socket), _) => stream_events(socket, receiver),
+            (None, Some(recorder)) => record_events(recorder, receiver),
+            (None, None) => write_events(path, receiver),
         }));
 
-        ChromeTracerGuard { sender, handle }
+        ChromeTracerGuard {
+            sender,
+            handle,
+            long_poll: self.long_poll.filter(|_| self.long_poll_summary),
+            control,
+        }
     }
 
     #[inline]
@@ -148,41 +561,171 @@ where
     CURRENT.with(|c| {
         let mut tracer = c.borrow_mut();
         if tracer.is_none() {
-            *tracer = unsafe { GLOBAL.clone() };
-            tracer.as_mut().map(|t| t.tid = std::thread::current().id().as_u64().into());
+            *tracer = GLOBAL.get().cloned();
+            if let Some(t) = tracer.as_mut() {
+                t.tid = std::thread::current().id().as_u64().into();
+            }
         }
 
         f(tracer.as_ref())
     })
 }
 
+/// Makes `tracer` the tracer of the calling thread, recording under the thread's own id.
+pub(crate) fn install(mut tracer: ChromeTracer) {
+    tracer.tid = std::thread::current().id().as_u64().into();
+    CURRENT.with(|c| *c.borrow_mut() = Some(tracer));
+}
+
+/// Makes `tracer` the tracer of the calling thread, like [`install`], and returns the previous
+/// one so it can be restored.
+pub(crate) fn replace(tracer: Option<ChromeTracer>) -> Option<ChromeTracer> {
+    let tracer = tracer.map(|mut tracer| {
+        tracer.tid = std::thread::current().id().as_u64().into();
+        tracer
+    });
+    CURRENT.with(|c| c.replace(tracer))
+}
+
+/// Sends `event` to the current thread's tracer, if any, filling in the thread id.
+pub(crate) fn emit(event: SimpleEvent) {
+    let mut event = Some(event);
+    current(|tracer| {
+        if let (Some(tracer), Some(mut event)) = (tracer, event.take()) {
+            event.tid = tracer.tid;
+            tracer.trace(event);
+        }
+    })
+}
+
+/// Start of the current thread's tracer if events at `level` are recorded, for code that times
+/// its spans by hand.
+pub(crate) fn start_if(level: Level) -> Option<Instant> {
+    if !cfg!(feature = "off") && level::enabled(level) {
+        current(|tracer| tracer.map(|t| t.start))
+    } else {
+        None
+    }
+}
+
+/// Runs `f` with a tracer that keeps events in memory and returns what was recorded.
+///
+/// Only events from the calling thread, and from threads it starts through [`crate::thread`],
+/// are captured. The previous tracer of the thread is restored afterwards, so captures may be
+/// nested and run alongside an initialized tracer.
+pub fn capture<F>(f: F) -> Trace
+where
+    F: FnOnce(),
+{
+    struct Restore(Option<Option<ChromeTracer>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take().unwrap();
+            CURRENT.with(|c| *c.borrow_mut() = previous);
+        }
+    }
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let tracer = ChromeTracer {
+        start: Instant::now(),
+        sender: Some(sender),
+        tid: std::thread::current().id().as_u64().into(),
+        max_level: None,
+        path: PathBuf::new(),
+        socket: None,
+        control: None,
+        flight_recorder: 0,
+        triggers: Vec::new(),
+        trigger_interval: Duration::ZERO,
+        max_triggered: 0,
+        output_dir: PathBuf::new(),
+        min_duration: None,
+        long_poll: None,
+        long_poll_backtrace: false,
+        long_poll_summary: false,
+    };
+    let tid = tracer.tid;
+
+    let restore = Restore(Some(replace(Some(tracer))));
+    f();
+    drop(restore);
+
+    let pid = std::process::id().into();
+    let mut events = Vec::new();
+    if let Some(name) = std::thread::current().name() {
+        let mut builder = ChromeEvent::builder(SystemTime::UNIX_EPOCH);
+        builder
+            .name("thread_name")
+            .ph(EventType::Metadata)
+            .ts(0.0)
+            .pid(pid)
+            .tid(tid)
+            .arg(("name".to_string(), name.to_string()));
+        events.push(builder.build().unwrap());
+    }
+    for message in receiver.try_iter() {
+        if let ChromeTracerMessage::ChromeEvent(event) = message {
+            events.extend(event.to_chrome_events(pid));
+        }
+    }
+
+    Trace::from_events(events)
+}
+
+#[cfg(not(feature = "off"))]
 #[macro_export]
 macro_rules! event {
-    (name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr) => {
-
-        $crate::current(|tracer| {
-            if let Some(tracer) = tracer {
-                // use $crate::Recordable as _;
-
-                let event = $crate::SimpleEvent {
-                    name: $name,
-                    from: $from,
-                    to: $to,
-                    is_async: $is_async,
-                    tid: tracer.tid,
-                    //tid: std::thread::current().id(),
-                };
-
-                // let mut builder = $crate::ChromeEvent::builder(tracer.start);
-                // $name.record(&mut builder, "name");
-                // $(
-                //     $value.record(&mut builder, stringify!($key));
-                // )*
-
-                // let event = builder.build().unwrap();
-                tracer.trace(event);
-            }
-        })
+    (name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr, span: $span:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::event!(level: $crate::Level::Info, name: $name, from: $from, to: $to, is_async: $is_async, span: $span $(, $key = $value)*)
+    };
+    (name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::event!(level: $crate::Level::Info, name: $name, from: $from, to: $to, is_async: $is_async $(, $key = $value)*)
+    };
+    (level: $level:expr, name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr, span: $span:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::event!(@record $level, $name, $from, $to, $is_async, [$span] $(, $key = $value)*)
+    };
+    (level: $level:expr, name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::event!(@record $level, $name, $from, $to, $is_async, [] $(, $key = $value)*)
+    };
+    (@record $level:expr, $name:expr, $from:expr, $to:expr, $is_async:expr, [$($span:expr)?] $(, $key:ident = $value:expr)*) => {
+        if $crate::enabled($level) {
+            $crate::current(|tracer| {
+                if let Some(tracer) = tracer {
+                    // use $crate::Recordable as _;
+
+                    let event = $crate::SimpleEvent::span(
+                        $name,
+                        $from,
+                        $to,
+                        $is_async,
+                        tracer.tid,
+                        //std::thread::current().id(),
+                        vec![$((stringify!($key), $value.to_string())),*],
+                    )$(.with_span($span))?;
+
+                    // let mut builder = $crate::ChromeEvent::builder(tracer.start);
+                    // $name.record(&mut builder, "name");
+                    // $(
+                    //     $value.record(&mut builder, stringify!($key));
+                    // )*
+
+                    // let event = builder.build().unwrap();
+                    tracer.trace(event);
+                }
+            })
+        }
+    };
+}
+
+#[cfg(feature = "off")]
+#[macro_export]
+macro_rules! event {
+    ($(level: $level:expr,)? name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr, span: $span:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        ()
+    };
+    ($(level: $level:expr,)? name: $name:expr, from: $from:expr, to: $to:expr, is_async: $is_async:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        ()
     };
 }
 
@@ -256,11 +799,27 @@ impl Recordable for EventType {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(not(feature = "off"))]
     #[test]
     fn event() {
-        let _guard = crate::builder().init();
+        let trace = crate::capture(|| {
+            event!(name: "hello", from: std::time::Duration::from_secs(1), to: std::time::Duration::from_secs(2), is_async: true, n = 1);
+        });
+
+        let span = trace.span("hello").unwrap();
+        assert!(span.is_async);
+        assert_eq!(span.duration(), std::time::Duration::from_secs(1));
+        assert_eq!(span.arg("n"), Some("1"));
+    }
+
+    #[cfg(not(feature = "off"))]
+    #[test]
+    fn with_level() {
+        let trace = crate::capture(|| {
+            event!(level: crate::Level::Trace, name: "hello", from: std::time::Duration::from_secs(1), to: std::time::Duration::from_secs(2), is_async: false);
+        });
 
-        event!(name: "hello", from: std::time::Duration::from_secs(1), to: std::time::Duration::from_secs(2), is_async: true);
+        assert_eq!(trace.spans.len(), 1);
     }
 
     #[test]
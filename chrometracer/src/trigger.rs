@@ -0,0 +1,122 @@
+//! Conditions that save the recent history of a process when something unusual happens.
+//!
+//! Triggers are evaluated by the writer thread as events arrive. When one fires, the events of
+//! the preceding [`Trigger::before`] still held by the flight recorder are written to a file
+//! named `trigger-<time>.json` in the tracer's `output_dir`, followed by the events of the next
+//! [`Trigger::after`], and a `trigger` instant event marks what fired. At most one triggered
+//! capture is written at a time, new ones wait for the tracer's `trigger_interval` since the
+//! last, and triggers stop firing after `max_triggered` captures.
+//!
+//! Triggers are not evaluated by a tracer streaming to a collector.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use chrometracer::trigger::Trigger;
+//!
+//! let _guard = chrometracer::builder()
+//!     .trigger(Trigger::span_longer("infer_batch", Duration::from_millis(20)))
+//!     .trigger(Trigger::counter_above("queue_depth", 1000.0).after(Duration::from_secs(5)))
+//!     .init();
+//! ```
+
+use std::time::Duration;
+
+use tracing_chrometrace::EventType;
+
+use crate::SimpleEvent;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Condition {
+    SpanLonger(Duration),
+    CounterAbove(f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trigger {
+    name: String,
+    condition: Condition,
+    pub(crate) before: Duration,
+    pub(crate) after: Duration,
+}
+
+impl Trigger {
+    fn new(name: impl Into<String>, condition: Condition) -> Trigger {
+        Trigger {
+            name: name.into(),
+            condition,
+            before: Duration::from_secs(10),
+            after: Duration::from_secs(2),
+        }
+    }
+
+    /// Fires when a span, synchronous or async, named `name` lasts longer than `threshold`.
+    pub fn span_longer(name: impl Into<String>, threshold: Duration) -> Trigger {
+        Trigger::new(name, Condition::SpanLonger(threshold))
+    }
+
+    /// Fires when a value of the counter named `name` exceeds `threshold`.
+    pub fn counter_above(name: impl Into<String>, threshold: f64) -> Trigger {
+        Trigger::new(name, Condition::CounterAbove(threshold))
+    }
+
+    /// History to keep from before the event that fired, 10 seconds by default.
+    pub fn before(mut self, before: Duration) -> Trigger {
+        self.before = before;
+        self
+    }
+
+    /// How long to keep writing events after the trigger fired, 2 seconds by default.
+    pub fn after(mut self, after: Duration) -> Trigger {
+        self.after = after;
+        self
+    }
+
+    /// Why `event` fires the trigger, if it does.
+    pub(crate) fn fired_by(&self, event: &SimpleEvent) -> Option<String> {
+        if event.name != self.name {
+            return None;
+        }
+        match self.condition {
+            Condition::SpanLonger(threshold) => {
+                let duration = event.to.saturating_sub(event.from);
+                let span = matches!(event.ph, EventType::Complete | EventType::AsyncStart);
+                (span && duration > threshold).then(|| format!("span {} took {:?}", self.name, duration))
+            }
+            Condition::CounterAbove(threshold) => {
+                if event.ph != EventType::Counter {
+                    return None;
+                }
+                event
+                    .args
+                    .iter()
+                    .find(|(_, value)| value.parse::<f64>().is_ok_and(|value| value > threshold))
+                    .map(|(key, value)| format!("counter {} {} is {}", self.name, key, value))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions() {
+        let span = |name, micros| SimpleEvent::span(name, Duration::ZERO, Duration::from_micros(micros), false, 1, Vec::new());
+        let counter = |value: &str| SimpleEvent {
+            ph: EventType::Counter,
+            args: vec![("depth", value.to_string())],
+            ..span("queue", 0)
+        };
+
+        let slow = Trigger::span_longer("infer", Duration::from_millis(20));
+        assert_eq!(slow.fired_by(&span("infer", 25_000)).as_deref(), Some("span infer took 25ms"));
+        assert_eq!(slow.fired_by(&span("infer", 20_000)), None);
+        assert_eq!(slow.fired_by(&span("other", 25_000)), None);
+
+        let deep = Trigger::counter_above("queue", 1000.0);
+        assert_eq!(deep.fired_by(&counter("1001")).as_deref(), Some("counter queue depth is 1001"));
+        assert_eq!(deep.fired_by(&counter("1000")), None);
+        assert_eq!(deep.fired_by(&span("queue", 1)), None);
+    }
+}
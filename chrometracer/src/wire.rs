@@ -0,0 +1,244 @@
+//! The binary format events are streamed to a collector in.
+//!
+//! A stream is a sequence of frames, each a little-endian `u32` length followed by that many
+//! bytes: a kind byte and the fields of the frame. The first frame is a [`Hello`] identifying the
+//! process and anchoring its timestamps to the system clocks, and every later frame is one
+//! [`ChromeEvent`]. Strings are a `u32` length and UTF-8 bytes, optional values a flag byte
+//! followed by the value if the flag is 1.
+
+use std::{
+    borrow::Cow,
+    io::{self, Read, Write},
+    time::SystemTime,
+};
+
+use serde_json::Value;
+use tracing_chrometrace::{ChromeEvent, EventType};
+
+pub use crate::tracer::clock_anchor;
+
+const HELLO: u8 = 1;
+const EVENT: u8 = 2;
+
+/// Frames longer than this are rejected rather than allocated.
+const MAX_FRAME: usize = 64 << 20;
+
+/// Sent once at the start of a stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hello {
+    pub pid: u64,
+    /// Name of the process, usually its executable.
+    pub name: String,
+    /// `CLOCK_MONOTONIC` at the start of the tracer, when event timestamps are 0.
+    pub monotonic_ns: Option<u64>,
+    /// `CLOCK_REALTIME` at the start of the tracer.
+    pub realtime_ns: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum Frame {
+    Hello(Hello),
+    /// An event, with the pid of the stream's hello.
+    Event(ChromeEvent),
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn option<T>(&mut self, value: Option<T>, f: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                f(self, value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn finish<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&(self.buf.len() as u32).to_le_bytes())?;
+        writer.write_all(&self.buf)
+    }
+}
+
+pub fn write_hello<W: Write>(writer: &mut W, hello: &Hello) -> io::Result<()> {
+    let mut e = Encoder::default();
+    e.u8(HELLO);
+    e.u64(hello.pid);
+    e.str(&hello.name);
+    e.option(hello.monotonic_ns, Encoder::u64);
+    e.option(hello.realtime_ns, Encoder::u64);
+    e.finish(writer)
+}
+
+/// Writes `event`, leaving out its pid.
+pub fn write_event<W: Write>(writer: &mut W, event: &ChromeEvent) -> io::Result<()> {
+    let ph = match serde_json::to_value(event.ph)? {
+        Value::String(ph) => ph,
+        _ => unreachable!("event types serialize to strings"),
+    };
+
+    let mut e = Encoder::default();
+    e.u8(EVENT);
+    e.str(&ph);
+    e.str(&event.name);
+    e.str(&event.cat);
+    e.f64(event.ts);
+    e.option(event.dur, Encoder::f64);
+    e.str(&event.id);
+    e.u64(event.tid);
+    e.u64(event.args.len() as u64);
+    for (key, value) in &event.args {
+        e.str(key);
+        e.str(value);
+    }
+    e.finish(writer)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("frame ends early"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let len = u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> io::Result<T>) -> io::Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err(invalid("bad option flag")),
+        }
+    }
+}
+
+/// Reads the next frame, or `None` at the end of the stream. A stream ending inside a frame,
+/// as when the sender crashed, is an `UnexpectedEof` error.
+pub fn read_frame<R: Read>(reader: &mut R, pid: u64) -> io::Result<Option<Frame>> {
+    let mut len = [0u8; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid("frame too long"));
+    }
+
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    let mut d = Decoder { buf: &buf };
+
+    match d.u8()? {
+        HELLO => Ok(Some(Frame::Hello(Hello {
+            pid: d.u64()?,
+            name: d.str()?,
+            monotonic_ns: d.option(Decoder::u64)?,
+            realtime_ns: d.option(Decoder::u64)?,
+        }))),
+        EVENT => {
+            let ph: EventType = serde_json::from_value(Value::String(d.str()?))?;
+            let mut builder = ChromeEvent::builder(SystemTime::UNIX_EPOCH);
+            builder
+                .ph(ph)
+                .name(Cow::Owned(d.str()?))
+                .cat(Cow::Owned(d.str()?))
+                .ts(d.f64()?)
+                .dur(d.option(Decoder::f64)?)
+                .id(Cow::Owned(d.str()?))
+                .tid(d.u64()?)
+                .pid(pid);
+            for _ in 0..d.u64()? {
+                builder.arg((d.str()?, d.str()?));
+            }
+            builder.build().map(|event| Some(Frame::Event(event))).map_err(|e| invalid(&e.to_string()))
+        }
+        _ => Err(invalid("unknown frame kind")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::SimpleEvent;
+
+    #[test]
+    fn round_trip() {
+        let hello = Hello {
+            pid: 42,
+            name: "worker".to_string(),
+            monotonic_ns: Some(1_000),
+            realtime_ns: None,
+        };
+        let span = SimpleEvent::span("work", Duration::from_micros(1), Duration::from_micros(3), false, 7, vec![("n", "1".to_string())]);
+
+        let mut stream = Vec::new();
+        write_hello(&mut stream, &hello).unwrap();
+        for event in span.to_chrome_events(42) {
+            write_event(&mut stream, &event).unwrap();
+        }
+        // A frame cut short, as by a crash of the sender.
+        stream.extend_from_slice(&[9, 0, 0, 0, EVENT]);
+
+        let mut reader = stream.as_slice();
+        assert!(matches!(read_frame(&mut reader, 0).unwrap(), Some(Frame::Hello(h)) if h == hello));
+        let Some(Frame::Event(event)) = read_frame(&mut reader, 42).unwrap() else {
+            panic!("expected an event");
+        };
+        assert_eq!(event, span.to_chrome_events(42).remove(0));
+        assert_eq!(read_frame(&mut reader, 42).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,46 @@
+//! A tracer given a collector socket streams its events there instead of writing a file.
+
+#![cfg(all(unix, not(feature = "off")))]
+
+use std::{os::unix::net::UnixListener, thread};
+
+use chrometracer::{
+    wire::{read_frame, Frame},
+    EventType,
+};
+
+#[chrometracer::instrument]
+fn work() {}
+
+#[test]
+fn streamed() {
+    let dir = std::env::temp_dir().join(format!("chrometracer-collector-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("collector.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let collector = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = read_frame(&mut stream, 0).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    });
+
+    let path = dir.join("trace.json");
+    let guard = chrometracer::builder().socket(&socket).path(&path).init();
+    work();
+    work();
+    drop(guard);
+
+    let frames = collector.join().unwrap();
+    // Nothing is written to the file while streaming.
+    assert!(!path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+    let Some(Frame::Hello(hello)) = frames.first() else {
+        panic!("the stream does not start with a hello");
+    };
+    assert_eq!(hello.pid, u64::from(std::process::id()));
+    let spans = frames.iter().filter(|frame| matches!(frame, Frame::Event(e) if e.name == "work" && e.ph == EventType::Complete));
+    assert_eq!(spans.count(), 2);
+}
@@ -0,0 +1,57 @@
+//! A tracer given a control socket keeps its events in memory until a capture is started.
+
+#![cfg(all(unix, not(feature = "off")))]
+
+use std::{path::Path, thread, time::Duration};
+
+use chrometracer::{control::request, Trace};
+
+#[chrometracer::instrument]
+fn before() {}
+
+#[chrometracer::instrument]
+fn during() {}
+
+/// Sends `request`, expecting it to succeed.
+fn ok(socket: &Path, line: &str) -> String {
+    let reply = request(socket, line).unwrap();
+    assert!(reply.starts_with("ok"), "{}: {}", line, reply);
+    reply
+}
+
+/// Waits until the tracer has seen `count` events.
+fn seen(socket: &Path, count: u64) {
+    let events = |stats: String| stats.split_whitespace().find_map(|field| field.strip_prefix("events="))?.parse::<u64>().ok();
+    while events(ok(socket, "stats")) < Some(count) {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn start_and_stop() {
+    let dir = std::env::temp_dir().join(format!("chrometracer-control-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("control.sock");
+    let path = dir.join("trace.json");
+    let guard = chrometracer::builder().control(&socket).path(&path).init();
+
+    // The clock anchor, then the span.
+    before();
+    seen(&socket, 2);
+
+    let capture = dir.join("capture.json");
+    ok(&socket, &format!("start --output {}", capture.display()));
+    during();
+    seen(&socket, 3);
+    assert_eq!(ok(&socket, "stop"), format!("ok wrote 2 events to {}", capture.display()));
+    before();
+    drop(guard);
+
+    // Events are only written by the capture, and the socket is gone with the tracer.
+    assert!(!path.exists());
+    assert!(!socket.exists());
+    let trace = Trace::open(&capture).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let names: Vec<_> = trace.spans.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["during"]);
+}
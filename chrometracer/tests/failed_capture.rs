@@ -0,0 +1,57 @@
+//! A capture that cannot be written ends on its own, without stopping the tracer.
+
+#![cfg(all(target_os = "linux", not(feature = "off")))]
+
+use std::{thread, time::Duration};
+
+use chrometracer::{control::request, Trace};
+
+#[chrometracer::instrument]
+fn work() {}
+
+/// Sends `request`, expecting it to succeed.
+fn ok(socket: &std::path::Path, line: &str) -> String {
+    let reply = request(socket, line).unwrap();
+    assert!(reply.starts_with("ok"), "{}: {}", line, reply);
+    reply
+}
+
+/// Number of events the tracer has seen so far.
+fn events(socket: &std::path::Path) -> u64 {
+    let stats = ok(socket, "stats");
+    let count = stats.split_whitespace().find_map(|field| field.strip_prefix("events="));
+    count.unwrap().parse().unwrap()
+}
+
+#[test]
+fn tracing_continues() {
+    let dir = std::env::temp_dir().join(format!("chrometracer-failed-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("control.sock");
+    let guard = chrometracer::builder().control(&socket).output_dir(&dir).init();
+
+    ok(&socket, "start --output /dev/full");
+    // Enough to fill the buffer, so the writes reach the full device.
+    for _ in 0..1_000 {
+        work();
+    }
+    // The clock anchor and the spans.
+    while events(&socket) < 1_001 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(ok(&socket, "stats").ends_with("capturing=none triggered=none"));
+
+    let capture = dir.join("capture.json");
+    ok(&socket, &format!("start --output {}", capture.display()));
+    let seen = events(&socket);
+    work();
+    while events(&socket) == seen {
+        thread::sleep(Duration::from_millis(10));
+    }
+    ok(&socket, "stop");
+    drop(guard);
+
+    let trace = Trace::open(&capture).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(trace.spans_named("work").count(), 1);
+}
@@ -0,0 +1,69 @@
+//! The level filters change global state, so they are tested in their own process.
+
+use chrometracer::{capture, enabled, max_level, set_max_level, Level, LevelFilter, STATIC_MAX_LEVEL};
+
+const LEVELS: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+#[chrometracer::instrument(level = 4)]
+fn debug() {}
+
+#[chrometracer::instrument(level = 5)]
+fn trace() {}
+
+#[chrometracer::instrument(level = "info")]
+fn info() {}
+
+#[test]
+fn runtime() {
+    set_max_level(LevelFilter::Debug);
+    assert_eq!(max_level(), LevelFilter::Debug);
+    assert!(!enabled(Level::Trace));
+
+    let recorded = capture(|| {
+        debug();
+        trace();
+    });
+    assert_eq!(recorded.spans_named("debug").count(), (Level::Debug <= STATIC_MAX_LEVEL && !cfg!(feature = "off")) as usize);
+    assert_eq!(recorded.spans_named("trace").count(), 0);
+
+    set_max_level(LevelFilter::Off);
+    assert!(LEVELS.iter().all(|&level| !enabled(level)));
+
+    set_max_level(LevelFilter::Trace);
+    for level in LEVELS {
+        assert_eq!(enabled(level), level <= STATIC_MAX_LEVEL);
+    }
+}
+
+#[test]
+fn compiled_out() {
+    let expected = if cfg!(feature = "max_level_off") {
+        LevelFilter::Off
+    } else if cfg!(feature = "max_level_error") {
+        LevelFilter::Error
+    } else if cfg!(feature = "max_level_warn") {
+        LevelFilter::Warn
+    } else if cfg!(feature = "max_level_info") {
+        LevelFilter::Info
+    } else if cfg!(feature = "max_level_debug") {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Trace
+    };
+    // Tests build with debug assertions, so the `release_max_level_*` features do not apply.
+    assert_eq!(STATIC_MAX_LEVEL, expected);
+
+    for level in LEVELS.into_iter().filter(|&level| level > STATIC_MAX_LEVEL) {
+        assert!(!enabled(level));
+    }
+    let recorded = capture(|| {
+        info();
+        trace();
+    });
+    if Level::Info > STATIC_MAX_LEVEL {
+        assert_eq!(recorded.spans_named("info").count(), 0);
+    }
+    if Level::Trace > STATIC_MAX_LEVEL {
+        assert_eq!(recorded.spans_named("trace").count(), 0);
+    }
+}
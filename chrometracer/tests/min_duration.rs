@@ -0,0 +1,35 @@
+//! The `min_duration` filter of a tracer drops short spans, except those inside slow ones.
+
+#![cfg(not(feature = "off"))]
+
+use std::{thread, time::Duration};
+
+use chrometracer::Trace;
+
+#[chrometracer::instrument]
+fn fast() {}
+
+#[chrometracer::instrument]
+fn slow() {
+    fast();
+    thread::sleep(Duration::from_millis(20));
+}
+
+#[chrometracer::instrument]
+fn alone() {}
+
+#[test]
+fn filtered() {
+    let path = std::env::temp_dir().join(format!("chrometracer-min-duration-{}.json", std::process::id()));
+    let guard = chrometracer::builder().min_duration(Duration::from_millis(10)).path(&path).init();
+    alone();
+    slow();
+    alone();
+    drop(guard);
+
+    let trace = Trace::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let names: Vec<_> = trace.spans.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["slow", "fast"]);
+    assert_eq!(trace.caller(trace.span("fast").unwrap()).unwrap().name, "slow");
+}
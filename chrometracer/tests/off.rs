@@ -0,0 +1,24 @@
+//! With the `off` feature, instrumentation compiles to the plain code and records nothing.
+//! Run with `cargo test -p chrometracer --features off`.
+
+#![cfg(feature = "off")]
+
+use chrometracer::{capture, event};
+
+#[chrometracer::instrument(level = "error", fields(n = 1), skip(value))]
+fn instrumented(value: u64) -> u64 {
+    value + 1
+}
+
+#[test]
+fn compiled_out() {
+    let trace = capture(|| {
+        assert_eq!(instrumented(1), 2);
+        // The arguments are dropped unevaluated.
+        let () = event!(name: "event", from: unreachable!(), to: unreachable!(), is_async: false, n = 1);
+        let () = event!(level: chrometracer::Level::Error, name: "event", from: unreachable!(), to: unreachable!(), is_async: false);
+    });
+
+    assert!(trace.spans.is_empty());
+    assert!(trace.instants.is_empty());
+}
@@ -1,4 +1,4 @@
-#[chrometracer::instrument]
+#[chrometracer::instrument(level = "debug")]
 fn bar() {
     println!("hello");
 }
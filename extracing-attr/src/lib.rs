@@ -39,6 +39,7 @@ impl Parse for Fields {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Clone)]
 enum Level {
     Str(LitStr),
@@ -207,7 +208,7 @@ pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
 
     if let syn::Item::Fn(ref mut item) = input {
         let original = &item.block;
-        item.block = Box::new(parse_quote! {{
+        *item.block = parse_quote! {{
             let start = chrometracer::current(|tracer| tracer.map(|t| t.start));
 
             if let Some(start) = start {
@@ -238,7 +239,7 @@ pub fn instrument(attr: TokenStream, item: TokenStream) -> TokenStream {
             } else {
                 #original
             }
-        }});
+        }};
     } else {
         unreachable!()
     }
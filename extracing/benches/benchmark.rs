@@ -1,6 +1,6 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use extracing::event;
-use chrometracer::{builder, event as cevent};
+use chrometracer::event as cevent;
 
 //#[chrometracer::instrument(fields(namee = format!("{}", "bye"), tid = 1))]
 #[chrometracer::instrument(fields(namee = format!("{}", "bye"), tid = 1), skip(a))]
@@ -8,6 +8,7 @@ fn hello() {
     //println!("HELLO WORLD");
 }
 
+#[allow(dead_code)]
 fn extreme_skip(c: &mut Criterion) {
     c.bench_function("span_noop", |b| {
         b.iter(|| {
@@ -2,7 +2,7 @@ use std::time::Instant;
 use std::cell::RefCell;
 
 thread_local! {
-    static ITEMS: RefCell<([u64; 8], usize)>  = RefCell::new(([0; 8], 0));
+    static ITEMS: RefCell<([u64; 8], usize)>  = const { RefCell::new(([0; 8], 0)) };
 }
 
 pub fn print_item() {
@@ -23,7 +23,7 @@ pub fn add_item(number: u64) {
         
         let mut a = x.borrow_mut();
         a.0[c] = number;
-        a.1 = a.1 + 1;
+        a.1 += 1;
         println!("elapsed 3 {}", from.elapsed().as_nanos());
     });
     println!("elapsed 4 {}", from.elapsed().as_nanos());
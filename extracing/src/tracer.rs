@@ -43,7 +43,7 @@ impl Tracer {
             let queue = ArrayQueue::new(1);
 
             while let Ok(Message::Span(value)) = rx.recv() {
-                if let Some(_) = queue.force_push(value) {
+                if queue.force_push(value).is_some() {
                     //println!("{}", v);
                 }
             }
@@ -70,7 +70,7 @@ impl Tracer {
 
 #[inline]
 pub fn get_global() -> &'static Option<Tracer> {
-    unsafe { &crate::tracer::GLOBAL }
+    unsafe { &*std::ptr::addr_of!(crate::tracer::GLOBAL) }
 }
 
 #[derive(Debug)]
@@ -123,7 +123,7 @@ mod tests {
     #[test]
     fn span() {
         let _guard = crate::tracer::Tracer::init();
-        let a = 5;
+        let _a = 5;
         event!(name = hello, i=1, f=3.4);
         event!(name = hello, i=2, f=4.1);
     }